@@ -0,0 +1,30 @@
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+
+#[path = "../../src/deserializer.rs"]
+#[allow(dead_code)]
+mod deserializer;
+
+use deserializer::GatewayEvent;
+
+// Payloads from clients are scanned for their opcode before anything else
+fuzz_target!(|data: &[u8]| {
+    let Ok(payload) = std::str::from_utf8(data) else {
+        return;
+    };
+
+    if let Some(event) = GatewayEvent::from_json(payload) {
+        let _op = event.op();
+        let (_, sequence, event_type) = event.into_parts();
+
+        // The ranges found are used to rewrite payloads, so they have to be valid
+        if let Some(deserializer::SequenceInfo(_, range)) = sequence {
+            assert!(payload.get(range).is_some());
+        }
+
+        if let Some(deserializer::EventTypeInfo(_, range)) = event_type {
+            assert!(payload.get(range).is_some());
+        }
+    }
+});
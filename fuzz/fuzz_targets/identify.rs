@@ -0,0 +1,44 @@
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+
+#[path = "../../src/deserializer.rs"]
+#[allow(dead_code)]
+mod deserializer;
+#[path = "../../src/model.rs"]
+#[allow(dead_code)]
+mod model;
+
+use deserializer::GatewayEvent;
+use model::Identify;
+
+// Mirrors how an IDENTIFY from a client is handled before it is trusted
+fuzz_target!(|data: &[u8]| {
+    let Ok(payload) = std::str::from_utf8(data) else {
+        return;
+    };
+
+    let Some(event) = GatewayEvent::from_json(payload) else {
+        return;
+    };
+
+    if event.op() != 2 {
+        return;
+    }
+
+    // simd-json parses in place, so it gets a copy like in the proxy
+    #[cfg(feature = "simd-json")]
+    let mut payload = payload.to_owned();
+    #[cfg(feature = "simd-json")]
+    let identify = unsafe { simd_json::from_str::<Identify>(&mut payload) };
+    #[cfg(not(feature = "simd-json"))]
+    let identify = serde_json::from_str::<Identify>(payload);
+
+    let Ok(identify) = identify else {
+        return;
+    };
+
+    let &[_shard_id, _shard_count] = identify.d.shard.as_slice() else {
+        return;
+    };
+});
@@ -0,0 +1,340 @@
+use http_body_util::Full;
+use hyper::{
+    body::{Bytes, Incoming},
+    header::{AUTHORIZATION, CONTENT_TYPE},
+    Method, Request, Response, StatusCode,
+};
+use ring::constant_time::verify_slices_are_equal;
+use serde::Serialize;
+#[cfg(not(feature = "simd-json"))]
+use serde_json::to_string;
+#[cfg(feature = "simd-json")]
+use simd_json::to_string;
+use tracing::info;
+
+use std::{collections::HashMap, net::SocketAddr, sync::atomic::Ordering};
+
+use crate::{
+    compression::Compression,
+    config::{Cache, CacheProfile, CONFIG},
+    dispatch::BroadcastMessage,
+    server::empty_response,
+    state::{ClientInfo as ConnectedClient, State},
+    SHUTDOWN_REQUESTED,
+};
+
+/// Response of the `/clients` endpoint.
+#[derive(Serialize)]
+struct ClientsInfo {
+    shards: Vec<ShardClients>,
+    /// Clients that haven't identified yet.
+    pending: Vec<ClientInfo>,
+}
+
+#[derive(Serialize)]
+struct ShardClients {
+    id: u32,
+    clients: Vec<ClientInfo>,
+}
+
+#[derive(Serialize)]
+struct ClientInfo {
+    id: u64,
+    addr: SocketAddr,
+    connected_secs: u64,
+    encoding: &'static str,
+    compression: Option<&'static str>,
+    compress: Option<bool>,
+    intents: Option<u64>,
+}
+
+impl ClientInfo {
+    fn new(id: u64, client: &ConnectedClient) -> Self {
+        Self {
+            id,
+            addr: client.addr,
+            connected_secs: client.connected_at.elapsed().as_secs(),
+            encoding: client.encoding.name(),
+            compression: client.compression.map(Compression::name),
+            compress: client.compress,
+            intents: client.intents,
+        }
+    }
+}
+
+/// Response of the `/debug/client/{id}` endpoint.
+#[derive(Serialize)]
+struct ClientDebugInfo {
+    #[serde(flatten)]
+    client: ClientInfo,
+    shard_id: Option<u32>,
+    /// Last sequence sent to the client.
+    seq: Option<usize>,
+    /// Messages waiting to be written to the client's websocket.
+    queued: usize,
+    /// Seconds since the client's last heartbeat.
+    last_heartbeat_secs: Option<u64>,
+}
+
+/// Response of the `/config` endpoint. Tokens and secrets are left out.
+#[derive(Serialize)]
+struct ConfigInfo<'a> {
+    bot: &'a str,
+    intents: u64,
+    shard_count: u32,
+    shard_start: Option<u32>,
+    shard_end: Option<u32>,
+    port: u16,
+    metrics_port: Option<u16>,
+    log_level: &'a str,
+    externally_accessible_url: &'a str,
+    route_prefix: &'a str,
+    gateway_url: Option<&'a str>,
+    cache: &'a Cache,
+    cache_profiles: &'a HashMap<String, CacheProfile>,
+    event_types: Vec<&'static str>,
+    backpressure: usize,
+    validate_token: bool,
+    client_secret: bool,
+    read_only: bool,
+    filter_client_intents: bool,
+    max_clients: Option<usize>,
+    max_message_size: usize,
+    compression_buffer_size: usize,
+    identify_timeout_ms: u64,
+    idle_timeout_ms: Option<u64>,
+    session_timeout_ms: u64,
+    heartbeat_interval: u64,
+    chunk_guilds_on_ready: bool,
+    drop_events: &'a [String],
+}
+
+/// Check the `Authorization` header of a request against the `admin_token`.
+/// Without an `admin_token`, the admin routes don't exist.
+pub fn authorize(request: &Request<Incoming>) -> Result<(), StatusCode> {
+    let Some(token) = &CONFIG.admin_token else {
+        return Err(StatusCode::NOT_FOUND);
+    };
+
+    let authorized = request
+        .headers()
+        .get(AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.split_whitespace().last())
+        .is_some_and(|provided| {
+            verify_slices_are_equal(provided.as_bytes(), token.as_bytes()).is_ok()
+        });
+
+    if authorized {
+        Ok(())
+    } else {
+        Err(StatusCode::UNAUTHORIZED)
+    }
+}
+
+/// Handle a request to the admin API below `/admin/`.
+pub fn handler(request: &Request<Incoming>, state: &State, path: &str) -> Response<Full<Bytes>> {
+    if let Err(status) = authorize(request) {
+        return empty_response(status);
+    }
+
+    let mut segments = path.split('/');
+
+    match (
+        segments.next(),
+        segments.next(),
+        segments.next(),
+        segments.next(),
+    ) {
+        (Some("shards"), Some(shard_id), Some(action), None) => {
+            let Some(shard) = shard_id.parse().ok().and_then(|id| state.shard(id)) else {
+                return empty_response(StatusCode::NOT_FOUND);
+            };
+
+            if request.method() != Method::POST {
+                return empty_response(StatusCode::METHOD_NOT_ALLOWED);
+            }
+
+            match action {
+                "resync" => {
+                    info!(
+                        "[Shard {}] Resyncing {} clients",
+                        shard.id,
+                        shard.events.receiver_count()
+                    );
+                    let _res = shard.events.send(BroadcastMessage::Resync);
+
+                    empty_response(StatusCode::NO_CONTENT)
+                }
+                "reconnect" => {
+                    info!("[Shard {}] Reconnecting to Discord", shard.id);
+                    shard.reconnect();
+
+                    empty_response(StatusCode::NO_CONTENT)
+                }
+                _ => empty_response(StatusCode::NOT_FOUND),
+            }
+        }
+        (Some("channels"), Some(channel_id), Some("reactions"), None) => {
+            if !CONFIG.cache.reactions {
+                return empty_response(StatusCode::NOT_FOUND);
+            }
+
+            if request.method() != Method::GET {
+                return empty_response(StatusCode::METHOD_NOT_ALLOWED);
+            }
+
+            // Channels aren't tied to a shard, so every shard's cache is asked
+            let Some(reactions) = channel_id.parse().ok().and_then(|channel_id| {
+                state
+                    .shards
+                    .iter()
+                    .find_map(|shard| shard.guilds.channel_reactions(channel_id))
+            }) else {
+                return empty_response(StatusCode::NOT_FOUND);
+            };
+
+            Response::builder()
+                .status(StatusCode::OK)
+                .header(CONTENT_TYPE, "application/json")
+                .body(Full::from(to_string(&reactions).unwrap()))
+                .unwrap()
+        }
+        (Some("shutdown"), None, None, None) => {
+            if request.method() != Method::POST {
+                return empty_response(StatusCode::METHOD_NOT_ALLOWED);
+            }
+
+            info!("Shutdown requested through the admin API");
+            SHUTDOWN_REQUESTED.notify_one();
+
+            empty_response(StatusCode::ACCEPTED)
+        }
+        _ => empty_response(StatusCode::NOT_FOUND),
+    }
+}
+
+/// List the clients connected to a bot, grouped by shard.
+pub fn clients(request: &Request<Incoming>, state: &State) -> Response<Full<Bytes>> {
+    if let Err(status) = authorize(request) {
+        return empty_response(status);
+    }
+
+    let mut info = ClientsInfo {
+        shards: state
+            .shards
+            .iter()
+            .map(|shard| ShardClients {
+                id: shard.id,
+                clients: Vec::new(),
+            })
+            .collect(),
+        pending: Vec::new(),
+    };
+
+    for (id, client) in state.clients.all() {
+        let client_info = ClientInfo::new(id, &client);
+
+        let shard = client
+            .shard_id
+            .and_then(|shard_id| info.shards.iter_mut().find(|shard| shard.id == shard_id));
+
+        match shard {
+            Some(shard) => shard.clients.push(client_info),
+            None => info.pending.push(client_info),
+        }
+    }
+
+    Response::builder()
+        .status(StatusCode::OK)
+        .header(CONTENT_TYPE, "application/json")
+        .body(Full::from(to_string(&info).unwrap()))
+        .unwrap()
+}
+
+/// Show the live state of a single client by its connection ID, as listed by
+/// `/clients`.
+pub fn client(request: &Request<Incoming>, state: &State, id: &str) -> Response<Full<Bytes>> {
+    if let Err(status) = authorize(request) {
+        return empty_response(status);
+    }
+
+    if request.method() != Method::GET {
+        return empty_response(StatusCode::METHOD_NOT_ALLOWED);
+    }
+
+    let Some((id, client)) = id
+        .parse()
+        .ok()
+        .and_then(|id| Some((id, state.clients.get(id)?)))
+    else {
+        return empty_response(StatusCode::NOT_FOUND);
+    };
+
+    let info = ClientDebugInfo {
+        client: ClientInfo::new(id, &client),
+        shard_id: client.shard_id,
+        seq: client.seq.map(|seq| seq.load(Ordering::Relaxed)),
+        queued: client.activity.queued.load(Ordering::Relaxed),
+        last_heartbeat_secs: client
+            .activity
+            .last_heartbeat
+            .lock()
+            .unwrap()
+            .map(|at| at.elapsed().as_secs()),
+    };
+
+    Response::builder()
+        .status(StatusCode::OK)
+        .header(CONTENT_TYPE, "application/json")
+        .body(Full::from(to_string(&info).unwrap()))
+        .unwrap()
+}
+
+/// Show the configuration that a bot runs with, including defaults.
+pub fn config(request: &Request<Incoming>, state: &State) -> Response<Full<Bytes>> {
+    if let Err(status) = authorize(request) {
+        return empty_response(status);
+    }
+
+    let info = ConfigInfo {
+        bot: &state.name,
+        intents: state.intents.bits(),
+        shard_count: state.shard_count,
+        shard_start: state.shards.first().map(|shard| shard.id),
+        shard_end: state.shards.last().map(|shard| shard.id + 1),
+        port: CONFIG.port,
+        metrics_port: CONFIG.metrics_port,
+        log_level: &CONFIG.log_level,
+        externally_accessible_url: &CONFIG.externally_accessible_url,
+        route_prefix: &CONFIG.route_prefix,
+        gateway_url: CONFIG.gateway_url.as_deref(),
+        cache: &CONFIG.cache,
+        cache_profiles: &CONFIG.cache_profiles,
+        event_types: CONFIG
+            .event_types
+            .iter_names()
+            .map(|(name, _)| name)
+            .collect(),
+        backpressure: CONFIG.backpressure,
+        validate_token: CONFIG.validate_token,
+        client_secret: CONFIG.client_secret.is_some(),
+        read_only: CONFIG.read_only,
+        filter_client_intents: CONFIG.filter_client_intents,
+        max_clients: CONFIG.max_clients,
+        max_message_size: CONFIG.max_message_size,
+        compression_buffer_size: CONFIG.compression_buffer_size,
+        identify_timeout_ms: CONFIG.identify_timeout_ms,
+        idle_timeout_ms: CONFIG.idle_timeout_ms,
+        session_timeout_ms: CONFIG.session_timeout_ms,
+        heartbeat_interval: CONFIG.heartbeat_interval,
+        chunk_guilds_on_ready: CONFIG.chunk_guilds_on_ready,
+        drop_events: &CONFIG.drop_events,
+    };
+
+    Response::builder()
+        .status(StatusCode::OK)
+        .header(CONTENT_TYPE, "application/json")
+        .body(Full::from(to_string(&info).unwrap()))
+        .unwrap()
+}
@@ -5,17 +5,25 @@ use serde::Serialize;
 use serde_json::{to_string, Value as OwnedValue};
 #[cfg(feature = "simd-json")]
 use simd_json::{to_string, OwnedValue};
-use twilight_cache_inmemory::{DefaultCacheModels, InMemoryCache, InMemoryCacheStats, UpdateCache};
+use twilight_cache_inmemory::{
+    model::CachedGuild, DefaultCacheModels, InMemoryCache, InMemoryCacheStats, UpdateCache,
+};
 use twilight_model::{
-    channel::{message::Sticker, Channel, StageInstance},
+    channel::{
+        message::{Reaction, Sticker},
+        Channel, StageInstance,
+    },
     gateway::{
-        payload::incoming::GuildDelete,
+        payload::{
+            incoming::{GuildDelete, MemberChunk},
+            outgoing::request_guild_members::{RequestGuildMemberId, RequestGuildMembersInfo},
+        },
         presence::{Presence, UserOrId},
         OpCode,
     },
     guild::{scheduled_event::GuildScheduledEvent, Emoji, Guild, Member, Role},
     id::{
-        marker::{GuildMarker, UserMarker},
+        marker::{ChannelMarker, GuildMarker, MessageMarker, UserMarker},
         Id,
     },
     voice::VoiceState,
@@ -23,7 +31,13 @@ use twilight_model::{
 
 use std::sync::Arc;
 
-use crate::model::JsonObject;
+use crate::{
+    config::{CacheProfile, CONFIG},
+    model::JsonObject,
+};
+
+/// Maximum amount of members in a single `GUILD_MEMBERS_CHUNK`, same as Discord.
+const MEMBERS_PER_CHUNK: usize = 1000;
 
 #[derive(Serialize)]
 pub struct Payload<T> {
@@ -33,6 +47,13 @@ pub struct Payload<T> {
     pub s: usize,
 }
 
+/// Reactions on a cached message.
+#[derive(Serialize)]
+pub struct MessageReactions {
+    pub message_id: Id<MessageMarker>,
+    pub reactions: Vec<Reaction>,
+}
+
 pub struct Guilds(Arc<InMemoryCache>);
 
 impl Guilds {
@@ -48,6 +69,10 @@ impl Guilds {
         self.0.stats()
     }
 
+    pub fn current_user_id(&self) -> Option<Id<UserMarker>> {
+        self.0.current_user().map(|user| user.id)
+    }
+
     pub fn get_ready_payload(
         &self,
         mut ready: JsonObject,
@@ -124,24 +149,26 @@ impl Guilds {
             .map(|reference| {
                 reference
                     .iter()
-                    .filter_map(|user_id| {
-                        let presence = self.0.presence(guild_id, *user_id)?;
-
-                        Some(Presence {
-                            activities: presence.activities().to_vec(),
-                            client_status: presence.client_status().clone(),
-                            guild_id: presence.guild_id(),
-                            status: presence.status(),
-                            user: UserOrId::UserId {
-                                id: presence.user_id(),
-                            },
-                        })
-                    })
+                    .filter_map(|user_id| self.presence(guild_id, *user_id))
                     .collect()
             })
             .unwrap_or_default()
     }
 
+    fn presence(&self, guild_id: Id<GuildMarker>, user_id: Id<UserMarker>) -> Option<Presence> {
+        let presence = self.0.presence(guild_id, user_id)?;
+
+        Some(Presence {
+            activities: presence.activities().to_vec(),
+            client_status: presence.client_status().clone(),
+            guild_id: presence.guild_id(),
+            status: presence.status(),
+            user: UserOrId::UserId {
+                id: presence.user_id(),
+            },
+        })
+    }
+
     fn emojis_in_guild(&self, guild_id: Id<GuildMarker>) -> Vec<Emoji> {
         self.0
             .guild_emojis(guild_id)
@@ -317,97 +344,394 @@ impl Guilds {
             .unwrap_or_default()
     }
 
-    pub fn get_guild_payloads<'a>(
-        &'a self,
-        sequence: &'a mut usize,
-    ) -> impl Iterator<Item = String> + 'a {
-        self.0.iter().guilds().map(move |guild| {
-            *sequence += 1;
+    /// Reactions on the cached messages of a channel, in the order that the
+    /// cache keeps the messages in, or `None` if none of them are cached.
+    pub fn channel_reactions(
+        &self,
+        channel_id: Id<ChannelMarker>,
+    ) -> Option<Vec<MessageReactions>> {
+        let message_ids = self.0.channel_messages(channel_id)?;
+
+        Some(
+            message_ids
+                .iter()
+                .filter_map(|&message_id| {
+                    let message = self.0.message(message_id)?;
+
+                    Some(MessageReactions {
+                        message_id,
+                        reactions: message.reactions().to_vec(),
+                    })
+                })
+                .collect(),
+        )
+    }
 
-            if guild.unavailable() {
-                to_string(&Payload {
-                    d: GuildDelete {
-                        id: guild.id(),
-                        unavailable: true,
-                    },
-                    op: OpCode::Dispatch,
-                    t: "GUILD_DELETE",
-                    s: *sequence,
+    /// Whether all members of a guild are cached, which is required to answer
+    /// member requests from the cache.
+    pub fn has_all_members(&self, guild_id: Id<GuildMarker>) -> bool {
+        let Some(member_count) = self
+            .0
+            .guild(guild_id)
+            .and_then(|guild| guild.member_count())
+        else {
+            return false;
+        };
+
+        self.0
+            .guild_members(guild_id)
+            .is_some_and(|members| members.len() as u64 == member_count)
+    }
+
+    /// Answer a member request from the cache, the same way Discord does.
+    pub fn get_member_chunks(
+        &self,
+        request: &RequestGuildMembersInfo,
+        sequence: &mut usize,
+    ) -> Vec<String> {
+        let guild_id = request.guild_id;
+        let mut not_found = Vec::new();
+
+        let members: Vec<Member> = if let Some(user_ids) = &request.user_ids {
+            let user_ids = match user_ids {
+                RequestGuildMemberId::One(user_id) => std::slice::from_ref(user_id),
+                RequestGuildMemberId::Multiple(user_ids) => user_ids.as_slice(),
+            };
+
+            user_ids
+                .iter()
+                .filter_map(|user_id| {
+                    let member = self.member(guild_id, *user_id);
+
+                    if member.is_none() {
+                        not_found.push(*user_id);
+                    }
+
+                    member
                 })
-                .unwrap()
+                .collect()
+        } else {
+            // Queries match the start of usernames and nicknames, a limit of 0 means all members
+            let query = request.query.as_deref().unwrap_or_default().to_lowercase();
+            let limit = request
+                .limit
+                .filter(|limit| *limit > 0)
+                .map_or(usize::MAX, |limit| limit as usize);
+
+            self.members_in_guild(guild_id)
+                .into_iter()
+                .filter(|member| {
+                    member.user.name.to_lowercase().starts_with(&query)
+                        || member
+                            .nick
+                            .as_ref()
+                            .is_some_and(|nick| nick.to_lowercase().starts_with(&query))
+                })
+                .take(limit)
+                .collect()
+        };
+
+        let chunk_count = members.len().div_ceil(MEMBERS_PER_CHUNK).max(1);
+        let mut chunks = Vec::with_capacity(chunk_count);
+        let mut members = members.into_iter();
+
+        for chunk_index in 0..chunk_count {
+            let members: Vec<Member> = members.by_ref().take(MEMBERS_PER_CHUNK).collect();
+
+            let presences = if request.presences == Some(true) {
+                members
+                    .iter()
+                    .filter_map(|member| self.presence(guild_id, member.user.id))
+                    .collect()
             } else {
-                let guild_channels = self.channels_in_guild(guild.id());
-                let presences = self.presences_in_guild(guild.id());
-                let emojis = self.emojis_in_guild(guild.id());
-                let members = self.members_in_guild(guild.id());
-                let roles = self.roles_in_guild(guild.id());
-                let scheduled_events = self.scheduled_events_in_guild(guild.id());
-                let stage_instances = self.stage_instances_in_guild(guild.id());
-                let stickers = self.stickers_in_guild(guild.id());
-                let voice_states = self.voice_states_in_guild(guild.id());
-                let threads = self.threads_in_guild(guild.id());
-
-                let new_guild = Guild {
-                    afk_channel_id: guild.afk_channel_id(),
-                    afk_timeout: guild.afk_timeout(),
-                    application_id: guild.application_id(),
-                    approximate_member_count: None, // Only present in with_counts HTTP endpoint
-                    banner: guild.banner().map(ToOwned::to_owned),
-                    approximate_presence_count: None, // Only present in with_counts HTTP endpoint
-                    channels: guild_channels,
-                    default_message_notifications: guild.default_message_notifications(),
-                    description: guild.description().map(ToString::to_string),
-                    discovery_splash: guild.discovery_splash().map(ToOwned::to_owned),
-                    emojis,
-                    explicit_content_filter: guild.explicit_content_filter(),
-                    features: guild.features().cloned().collect(),
-                    guild_scheduled_events: scheduled_events,
-                    icon: guild.icon().map(ToOwned::to_owned),
-                    id: guild.id(),
-                    joined_at: guild.joined_at(),
-                    large: guild.large(),
-                    max_members: guild.max_members(),
-                    max_presences: guild.max_presences(),
-                    max_video_channel_users: guild.max_video_channel_users(),
-                    member_count: guild.member_count(),
-                    members,
-                    mfa_level: guild.mfa_level(),
-                    name: guild.name().to_string(),
-                    nsfw_level: guild.nsfw_level(),
-                    owner_id: guild.owner_id(),
-                    owner: guild.owner(),
-                    permissions: guild.permissions(),
-                    public_updates_channel_id: guild.public_updates_channel_id(),
-                    preferred_locale: guild.preferred_locale().to_string(),
-                    premium_progress_bar_enabled: guild.premium_progress_bar_enabled(),
-                    premium_subscription_count: guild.premium_subscription_count(),
-                    premium_tier: guild.premium_tier(),
-                    presences,
-                    roles,
-                    rules_channel_id: guild.rules_channel_id(),
-                    safety_alerts_channel_id: guild.safety_alerts_channel_id(),
-                    splash: guild.splash().map(ToOwned::to_owned),
-                    stage_instances,
-                    stickers,
-                    system_channel_flags: guild.system_channel_flags(),
-                    system_channel_id: guild.system_channel_id(),
-                    threads,
-                    unavailable: false,
-                    vanity_url_code: guild.vanity_url_code().map(ToString::to_string),
-                    verification_level: guild.verification_level(),
-                    voice_states,
-                    widget_channel_id: guild.widget_channel_id(),
-                    widget_enabled: guild.widget_enabled(),
-                };
+                Vec::new()
+            };
 
+            *sequence += 1;
+
+            chunks.push(
                 to_string(&Payload {
-                    d: new_guild,
+                    d: MemberChunk {
+                        chunk_count: chunk_count as u32,
+                        chunk_index: chunk_index as u32,
+                        guild_id,
+                        members,
+                        nonce: request.nonce.clone(),
+                        // Only the first chunk reports users that weren't found
+                        not_found: if chunk_index == 0 {
+                            std::mem::take(&mut not_found)
+                        } else {
+                            Vec::new()
+                        },
+                        presences,
+                    },
                     op: OpCode::Dispatch,
-                    t: "GUILD_CREATE",
+                    t: "GUILD_MEMBERS_CHUNK",
                     s: *sequence,
                 })
-                .unwrap()
-            }
+                .unwrap(),
+            );
+        }
+
+        chunks
+    }
+
+    pub fn get_guild_payloads<'a>(
+        &'a self,
+        sequence: &'a mut usize,
+        profile: &'a CacheProfile,
+    ) -> impl Iterator<Item = (Id<GuildMarker>, String)> + 'a {
+        self.0.iter().guilds().map(move |guild| {
+            *sequence += 1;
+
+            (guild.id(), self.guild_payload(&guild, *sequence, profile))
         })
     }
+
+    /// IDs of all guilds in the cache, for building their payloads one by one
+    /// with `get_guild_payload`.
+    pub fn guild_ids(&self) -> Vec<Id<GuildMarker>> {
+        self.0.iter().guilds().map(|guild| guild.id()).collect()
+    }
+
+    /// Build the payload of a single guild, like `get_guild_payloads`, unless
+    /// it was removed from the cache in the meantime.
+    pub fn get_guild_payload(
+        &self,
+        guild_id: Id<GuildMarker>,
+        sequence: &mut usize,
+        profile: &CacheProfile,
+    ) -> Option<String> {
+        let guild = self.0.guild(guild_id)?;
+        *sequence += 1;
+
+        Some(self.guild_payload(&guild, *sequence, profile))
+    }
+
+    fn guild_payload(
+        &self,
+        guild: &CachedGuild,
+        sequence: usize,
+        profile: &CacheProfile,
+    ) -> String {
+        if guild.unavailable() {
+            to_string(&Payload {
+                d: GuildDelete {
+                    id: guild.id(),
+                    unavailable: true,
+                },
+                op: OpCode::Dispatch,
+                t: "GUILD_DELETE",
+                s: sequence,
+            })
+            .unwrap()
+        } else {
+            let guild_channels = self.channels_in_guild(guild.id());
+            let presences = if profile.presences {
+                self.presences_in_guild(guild.id())
+            } else {
+                Vec::new()
+            };
+            let emojis = self.emojis_in_guild(guild.id());
+            let members = if profile.members {
+                self.members_in_guild(guild.id())
+            } else {
+                Vec::new()
+            };
+            let roles = self.roles_in_guild(guild.id());
+            let scheduled_events = self.scheduled_events_in_guild(guild.id());
+            let stage_instances = self.stage_instances_in_guild(guild.id());
+            let stickers = self.stickers_in_guild(guild.id());
+            let voice_states = if profile.voice_states {
+                self.voice_states_in_guild(guild.id())
+            } else {
+                Vec::new()
+            };
+            let threads = self.threads_in_guild(guild.id());
+
+            let new_guild = Guild {
+                afk_channel_id: guild.afk_channel_id(),
+                afk_timeout: guild.afk_timeout(),
+                application_id: guild.application_id(),
+                approximate_member_count: None, // Only present in with_counts HTTP endpoint
+                banner: guild.banner().map(ToOwned::to_owned),
+                approximate_presence_count: None, // Only present in with_counts HTTP endpoint
+                channels: guild_channels,
+                default_message_notifications: guild.default_message_notifications(),
+                description: guild.description().map(ToString::to_string),
+                discovery_splash: guild.discovery_splash().map(ToOwned::to_owned),
+                emojis,
+                explicit_content_filter: guild.explicit_content_filter(),
+                features: guild.features().cloned().collect(),
+                guild_scheduled_events: scheduled_events,
+                icon: guild.icon().map(ToOwned::to_owned),
+                id: guild.id(),
+                joined_at: guild.joined_at(),
+                large: guild.large(),
+                max_members: guild.max_members(),
+                max_presences: guild.max_presences(),
+                max_video_channel_users: guild.max_video_channel_users(),
+                member_count: guild.member_count(),
+                members,
+                mfa_level: guild.mfa_level(),
+                name: guild.name().to_string(),
+                nsfw_level: guild.nsfw_level(),
+                owner_id: guild.owner_id(),
+                owner: guild.owner(),
+                permissions: guild.permissions(),
+                public_updates_channel_id: guild.public_updates_channel_id(),
+                preferred_locale: guild.preferred_locale().to_string(),
+                premium_progress_bar_enabled: guild.premium_progress_bar_enabled(),
+                premium_subscription_count: guild.premium_subscription_count(),
+                premium_tier: guild.premium_tier(),
+                presences,
+                roles,
+                rules_channel_id: guild.rules_channel_id(),
+                safety_alerts_channel_id: guild.safety_alerts_channel_id(),
+                splash: guild.splash().map(ToOwned::to_owned),
+                stage_instances,
+                stickers,
+                system_channel_flags: guild.system_channel_flags(),
+                system_channel_id: guild.system_channel_id(),
+                threads,
+                unavailable: false,
+                vanity_url_code: guild.vanity_url_code().map(ToString::to_string),
+                verification_level: guild.verification_level(),
+                voice_states,
+                widget_channel_id: guild.widget_channel_id(),
+                widget_enabled: guild.widget_enabled(),
+            };
+
+            guild_create_payload(new_guild, sequence)
+        }
+    }
+}
+
+/// Serialize a `GUILD_CREATE`. Clients can still request the members of guilds
+/// that are larger than `guild_payload_max_size`, so only these are left out.
+fn guild_create_payload(mut guild: Guild, sequence: usize) -> String {
+    let payload = to_string(&Payload {
+        d: &guild,
+        op: OpCode::Dispatch,
+        t: "GUILD_CREATE",
+        s: sequence,
+    })
+    .unwrap();
+
+    if CONFIG
+        .guild_payload_max_size
+        .is_none_or(|size| payload.len() <= size)
+    {
+        return payload;
+    }
+
+    guild.members.clear();
+    guild.presences.clear();
+    guild.voice_states.clear();
+
+    to_string(&Payload {
+        d: &guild,
+        op: OpCode::Dispatch,
+        t: "GUILD_CREATE",
+        s: sequence,
+    })
+    .unwrap()
+}
+
+#[cfg(test)]
+mod tests {
+    use twilight_cache_inmemory::InMemoryCache;
+    use twilight_model::{
+        gateway::payload::incoming::{GuildCreate, GuildDelete},
+        id::Id,
+    };
+
+    use std::sync::Arc;
+
+    use super::Guilds;
+    use crate::{
+        config::{CacheProfile, CONFIG},
+        deserializer::{find_guild_id, GatewayEvent},
+    };
+
+    fn guilds() -> Guilds {
+        let cache = InMemoryCache::builder()
+            .resource_types(CONFIG.cache.clone().into())
+            .build();
+
+        Guilds::new(Arc::new(cache))
+    }
+
+    fn guild_json(id: u64) -> String {
+        format!(
+            r#"{{"id":"{id}","name":"guild {id}","icon":null,"splash":null,"discovery_splash":null,"owner_id":"100","afk_channel_id":null,"afk_timeout":300,"widget_enabled":false,"verification_level":0,"default_message_notifications":0,"explicit_content_filter":0,"roles":[],"emojis":[],"stickers":[],"features":[],"mfa_level":0,"application_id":null,"system_channel_id":null,"system_channel_flags":0,"rules_channel_id":null,"safety_alerts_channel_id":null,"vanity_url_code":null,"description":null,"banner":null,"premium_tier":0,"premium_subscription_count":0,"preferred_locale":"en-US","public_updates_channel_id":null,"nsfw_level":0,"premium_progress_bar_enabled":false,"large":false,"member_count":1,"unavailable":false,"channels":[],"threads":[],"members":[],"presences":[],"voice_states":[],"stage_instances":[],"guild_scheduled_events":[]}}"#
+        )
+    }
+
+    fn guild_create(id: u64) -> GuildCreate {
+        #[cfg(feature = "simd-json")]
+        let guild = unsafe { simd_json::from_str(&mut guild_json(id)) }.unwrap();
+        #[cfg(not(feature = "simd-json"))]
+        let guild = serde_json::from_str(&guild_json(id)).unwrap();
+
+        GuildCreate::Available(guild)
+    }
+
+    /// Event name and sequence number of a replayed payload.
+    fn parts(payload: &str) -> (&str, u64) {
+        let (_, sequence, event_type) = GatewayEvent::from_json(payload).unwrap().into_parts();
+
+        (event_type.unwrap().0, sequence.unwrap().0)
+    }
+
+    #[test]
+    fn replays_guilds_with_consecutive_sequences() {
+        let guilds = guilds();
+        for id in 1..=3 {
+            guilds.update(guild_create(id));
+        }
+
+        let mut sequence = 10;
+        let payloads: Vec<_> = guilds
+            .get_guild_payloads(&mut sequence, &CacheProfile::ALL)
+            .collect();
+
+        for (expected, (guild_id, payload)) in (11..).zip(&payloads) {
+            assert_eq!(parts(payload), ("GUILD_CREATE", expected));
+            assert_eq!(find_guild_id(payload, "GUILD_CREATE"), Some(*guild_id));
+        }
+
+        let mut guild_ids: Vec<_> = payloads.iter().map(|(id, _)| id.get()).collect();
+        guild_ids.sort_unstable();
+
+        assert_eq!(guild_ids, [1, 2, 3]);
+        assert_eq!(sequence, 13);
+    }
+
+    #[test]
+    fn replays_unavailable_guild_until_available_again() {
+        let guilds = guilds();
+        guilds.update(guild_create(1));
+        guilds.update(GuildDelete {
+            id: Id::new(1),
+            unavailable: true,
+        });
+
+        let mut sequence = 0;
+        let payloads: Vec<_> = guilds
+            .get_guild_payloads(&mut sequence, &CacheProfile::ALL)
+            .collect();
+
+        assert_eq!(payloads.len(), 1);
+        assert_eq!(parts(&payloads[0].1), ("GUILD_DELETE", 1));
+
+        guilds.update(guild_create(1));
+        let payloads: Vec<_> = guilds
+            .get_guild_payloads(&mut sequence, &CacheProfile::ALL)
+            .collect();
+
+        assert_eq!(payloads.len(), 1);
+        assert_eq!(parts(&payloads[0].1), ("GUILD_CREATE", 2));
+        assert_eq!(sequence, 2);
+    }
 }
@@ -0,0 +1,256 @@
+use flate2::{Compress, Compression as Level, Decompress, FlushCompress, FlushDecompress, Status};
+
+const TRAILER: [u8; 4] = [0x00, 0x00, 0xff, 0xff];
+
+/// Header of a gzip member without modification time, flags or file name as
+/// specified in RFC1952: <https://datatracker.ietf.org/doc/html/rfc1952#section-2.3>
+const GZIP_HEADER: [u8; 10] = [0x1f, 0x8b, 0x08, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0xff];
+
+/// Transport compression used for a client connection.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Compression {
+    /// zlib-stream, as used by Discord.
+    Zlib,
+    /// A single gzip stream that is flushed after every message. Discord does
+    /// not support this, it is only offered for third-party clients.
+    Gzip,
+}
+
+impl Compression {
+    /// Parse the value of the `compress` query string parameter.
+    pub fn from_query(value: &str) -> Option<Self> {
+        match value {
+            "zlib-stream" => Some(Self::Zlib),
+            "gzip-stream" => Some(Self::Gzip),
+            _ => None,
+        }
+    }
+
+    /// Name of the compression as used in the query string.
+    pub const fn name(self) -> &'static str {
+        match self {
+            Self::Zlib => "zlib-stream",
+            Self::Gzip => "gzip-stream",
+        }
+    }
+}
+
+/// Compression stream for a single client.
+pub struct Compressor {
+    compress: Compress,
+    /// Bytes that still have to be written before the first message.
+    header: &'static [u8],
+    /// Flush after every message, which decides whether later messages may
+    /// refer back to earlier ones.
+    flush: FlushCompress,
+}
+
+impl Compressor {
+    /// Create a compression stream. Without context takeover, the compression
+    /// state is reset after every message, so that messages after the first
+    /// can be inflated on their own. Discord always uses context takeover.
+    pub fn new(compression: Compression, context_takeover: bool) -> Self {
+        // Initialize an encoder with similar settings to Discord's, gzip uses a
+        // raw deflate stream with its own header instead of the zlib one
+        let (compress, header) = match compression {
+            Compression::Zlib => (Compress::new(Level::fast(), true), &[][..]),
+            Compression::Gzip => (Compress::new(Level::fast(), false), &GZIP_HEADER[..]),
+        };
+
+        let flush = if context_takeover {
+            FlushCompress::Sync
+        } else {
+            FlushCompress::Full
+        };
+
+        Self {
+            compress,
+            header,
+            flush,
+        }
+    }
+
+    /// Compress a message into `output` and flush the stream so the client can
+    /// decompress the message right away.
+    pub fn compress(&mut self, output: &mut Vec<u8>, input: &[u8]) {
+        output.extend_from_slice(self.header);
+        self.header = &[];
+
+        compress_full(&mut self.compress, output, input, self.flush);
+    }
+}
+
+fn compress_full(
+    compressor: &mut Compress,
+    output: &mut Vec<u8>,
+    input: &[u8],
+    flush: FlushCompress,
+) {
+    let before_in = compressor.total_in() as usize;
+    while (compressor.total_in() as usize) - before_in < input.len() {
+        let offset = (compressor.total_in() as usize) - before_in;
+        match compressor
+            .compress_vec(&input[offset..], output, FlushCompress::None)
+            .unwrap()
+        {
+            Status::Ok => continue,
+            Status::BufError => output.reserve(4096),
+            Status::StreamEnd => break,
+        }
+    }
+
+    while !output.ends_with(&TRAILER) {
+        output.reserve(5);
+        match compressor.compress_vec(&[], output, flush).unwrap() {
+            Status::Ok | Status::BufError => continue,
+            Status::StreamEnd => break,
+        }
+    }
+}
+
+/// Payloads compressed by the self-test, sent one after another on the same
+/// stream like messages to a client.
+/// The last one repeats the first, which a compressor with context takeover
+/// would refer back to.
+const SELF_TEST_PAYLOADS: [&str; 3] = [
+    r#"{"t":null,"s":null,"op":10,"d":{"heartbeat_interval":41250}}"#,
+    r#"{"t":"RESUMED","s":null,"op":0,"d":{}}"#,
+    r#"{"t":null,"s":null,"op":10,"d":{"heartbeat_interval":41250}}"#,
+];
+
+/// Compress a few payloads and inflate them with a fresh decompressor, to make
+/// sure that messages are framed in a way that clients can decompress. Every
+/// message has to be inflated completely from its own bytes, so that a
+/// connection closed after any message leaves the client's decompressor at a
+/// sync point instead of in the middle of a block. Without context takeover,
+/// every message after the first also has to inflate with a decompressor of
+/// its own.
+pub fn self_test() -> Result<(), String> {
+    for (compression, context_takeover) in [
+        (Compression::Zlib, true),
+        (Compression::Gzip, true),
+        (Compression::Zlib, false),
+        (Compression::Gzip, false),
+    ] {
+        let mut compressor = Compressor::new(compression, context_takeover);
+        // The gzip header is skipped, the rest is a raw deflate stream
+        let mut decompress = Decompress::new(compression == Compression::Zlib);
+        let mut skip = match compression {
+            Compression::Zlib => 0,
+            Compression::Gzip => GZIP_HEADER.len(),
+        };
+
+        for (index, payload) in SELF_TEST_PAYLOADS.into_iter().enumerate() {
+            let mut compressed = Vec::new();
+            compressor.compress(&mut compressed, payload.as_bytes());
+
+            if !compressed.ends_with(&TRAILER) {
+                return Err(format!(
+                    "{compression:?} message does not end with a sync flush"
+                ));
+            }
+
+            let mut inflated = Vec::with_capacity(payload.len() * 2);
+            let before_in = decompress.total_in();
+            decompress
+                .decompress_vec(&compressed[skip..], &mut inflated, FlushDecompress::Sync)
+                .map_err(|e| format!("{compression:?} message can't be inflated: {e}"))?;
+
+            if decompress.total_in() - before_in != (compressed.len() - skip) as u64 {
+                return Err(format!(
+                    "{compression:?} message was not inflated up to its end"
+                ));
+            }
+
+            if inflated != payload.as_bytes() {
+                return Err(format!(
+                    "{compression:?} message was not inflated to the original"
+                ));
+            }
+
+            // The header only precedes the first message, the others are raw
+            // deflate data that must not depend on what came before
+            if !context_takeover && index > 0 {
+                let mut inflated = Vec::with_capacity(payload.len() * 2);
+                Decompress::new(false)
+                    .decompress_vec(&compressed, &mut inflated, FlushDecompress::Sync)
+                    .map_err(|e| {
+                        format!("{compression:?} message can't be inflated on its own: {e}")
+                    })?;
+
+                if inflated != payload.as_bytes() {
+                    return Err(format!(
+                        "{compression:?} message depends on earlier messages without context takeover"
+                    ));
+                }
+            }
+
+            skip = 0;
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use flate2::{Decompress, FlushDecompress};
+
+    use super::{Compression, Compressor, GZIP_HEADER, SELF_TEST_PAYLOADS};
+
+    fn compress(compressor: &mut Compressor, payload: &str) -> Vec<u8> {
+        let mut compressed = Vec::new();
+        compressor.compress(&mut compressed, payload.as_bytes());
+        compressed
+    }
+
+    fn inflate(decompress: &mut Decompress, compressed: &[u8]) -> Vec<u8> {
+        let mut inflated = Vec::with_capacity(4096);
+        decompress
+            .decompress_vec(compressed, &mut inflated, FlushDecompress::Sync)
+            .unwrap();
+        inflated
+    }
+
+    #[test]
+    fn inflates_later_messages_on_their_own_without_context_takeover() {
+        for compression in [Compression::Zlib, Compression::Gzip] {
+            let mut compressor = Compressor::new(compression, false);
+            compress(&mut compressor, SELF_TEST_PAYLOADS[0]);
+
+            for payload in &SELF_TEST_PAYLOADS[1..] {
+                let compressed = compress(&mut compressor, payload);
+
+                assert_eq!(
+                    inflate(&mut Decompress::new(false), &compressed),
+                    payload.as_bytes(),
+                    "{compression:?}"
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn inflates_stream_stopped_after_any_message() {
+        for compression in [Compression::Zlib, Compression::Gzip] {
+            let mut compressor = Compressor::new(compression, true);
+            let mut stream = Vec::new();
+            let mut expected = Vec::new();
+
+            for payload in SELF_TEST_PAYLOADS {
+                stream.extend(compress(&mut compressor, payload));
+                expected.extend_from_slice(payload.as_bytes());
+
+                // A client that reconnects now has received exactly this
+                let data = match compression {
+                    Compression::Zlib => &stream[..],
+                    Compression::Gzip => &stream[GZIP_HEADER.len()..],
+                };
+                let mut decompress = Decompress::new(compression == Compression::Zlib);
+
+                assert_eq!(inflate(&mut decompress, data), expected, "{compression:?}");
+                assert_eq!(decompress.total_in(), data.len() as u64, "{compression:?}");
+            }
+        }
+    }
+}
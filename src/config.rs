@@ -1,6 +1,10 @@
 use futures_util::StreamExt;
+use hyper::{http::uri::Authority, Uri};
 use inotify::{Inotify, WatchMask};
-use serde::Deserialize;
+use serde::{
+    de::{Error as DeError, SeqAccess, Visitor},
+    Deserialize, Deserializer, Serialize,
+};
 #[cfg(not(feature = "simd-json"))]
 use serde_json::Error as JsonError;
 #[cfg(feature = "simd-json")]
@@ -8,12 +12,17 @@ use simd_json::Error as JsonError;
 use tracing_subscriber::{filter::LevelFilter, reload};
 use twilight_cache_inmemory::ResourceType;
 use twilight_gateway::{EventTypeFlags, Intents};
-use twilight_model::gateway::presence::{Activity, Status};
+use twilight_model::gateway::{
+    event::EventType,
+    presence::{Activity, Status},
+};
 
 use std::{
+    collections::HashMap,
     env::var,
     fmt::{Display, Formatter, Result as FmtResult},
     fs::read_to_string,
+    net::IpAddr,
     process::exit,
     str::FromStr,
     sync::LazyLock,
@@ -23,12 +32,19 @@ use std::{
 pub struct Config {
     #[serde(default = "default_log_level")]
     pub log_level: String,
-    #[serde(default = "token_fallback")]
+    #[serde(default)]
     pub token: String,
+    #[serde(default)]
+    pub token_file: Option<String>,
+    #[serde(default)]
+    pub consumer_tokens: HashMap<String, String>,
+    #[serde(deserialize_with = "deserialize_intents")]
     pub intents: Intents,
     #[serde(default = "default_port")]
     pub port: u16,
     #[serde(default)]
+    pub metrics_port: Option<u16>,
+    #[serde(default)]
     pub shards: Option<u32>,
     #[serde(default)]
     pub shard_start: Option<u32>,
@@ -38,18 +54,275 @@ pub struct Config {
     pub activity: Option<Activity>,
     #[serde(default = "default_status")]
     pub status: Status,
+    #[serde(default)]
+    pub presence_rotation: Vec<RotatingPresence>,
     #[serde(default = "default_backpressure")]
     pub backpressure: usize,
+    #[serde(default)]
+    pub resume_buffer_size: usize,
+    #[serde(default)]
+    pub reconnect_buffer_size: usize,
+    #[serde(default)]
+    pub shard_connect_delay_ms: u64,
     #[serde(default = "default_validate_token")]
     pub validate_token: bool,
     #[serde(default)]
     pub twilight_http_proxy: Option<String>,
+    #[serde(default)]
+    pub gateway_url: Option<String>,
     pub externally_accessible_url: String,
     #[serde(default)]
     pub cache: Cache,
+    #[serde(default)]
+    pub cache_profiles: HashMap<String, CacheProfile>,
+    #[serde(default)]
+    pub strict_cache_intents: bool,
+    #[serde(default)]
+    pub slow_client_policy: SlowClientPolicy,
+    #[serde(default)]
+    pub slow_client_threshold: Option<usize>,
+    #[serde(default = "default_identify_timeout_ms")]
+    pub identify_timeout_ms: u64,
+    #[serde(default)]
+    pub max_invalid_identifies: Option<u32>,
+    #[serde(default = "default_max_message_size")]
+    pub max_message_size: usize,
+    #[serde(default = "default_compression_buffer_size")]
+    pub compression_buffer_size: usize,
+    #[serde(default)]
+    pub subprotocols: Vec<String>,
+    #[serde(default)]
+    pub strict_subprotocols: bool,
+    #[serde(default)]
+    pub close_backoff_ms: HashMap<u16, u64>,
+    #[serde(default)]
+    pub idle_timeout_ms: Option<u64>,
+    #[serde(default = "default_session_timeout_ms")]
+    pub session_timeout_ms: u64,
+    #[serde(default)]
+    pub chunk_guilds_on_ready: bool,
+    #[serde(default)]
+    pub first_client_real_ready: bool,
+    #[serde(default)]
+    pub client_secret: Option<String>,
+    #[serde(default)]
+    pub trust_x_forwarded_for: bool,
+    #[serde(default)]
+    pub trusted_proxies: Vec<IpAddr>,
+    #[serde(default)]
+    pub drop_events: Vec<String>,
+    #[serde(default)]
+    pub log_commands: bool,
+    #[serde(default)]
+    pub read_only: bool,
+    #[serde(default)]
+    pub voice_state_policy: VoiceStatePolicy,
+    #[serde(default)]
+    pub max_clients: Option<usize>,
+    #[serde(default)]
+    pub route_prefix: String,
+    #[serde(default)]
+    pub admin_token: Option<String>,
+    #[serde(default)]
+    pub guild_payload_warn_size: Option<usize>,
+    #[serde(default)]
+    pub guild_payload_max_size: Option<usize>,
+    #[serde(default)]
+    pub guild_replay_batch_size: Option<usize>,
+    #[serde(default)]
+    pub guild_replay_batch_pause_ms: u64,
+    #[serde(default)]
+    pub client_ratelimit: Option<ClientRatelimit>,
+    #[serde(
+        default = "EventTypeFlags::empty",
+        deserialize_with = "deserialize_event_types"
+    )]
+    pub event_types: EventTypeFlags,
+    #[serde(default)]
+    pub filter_client_intents: bool,
+    #[serde(default)]
+    pub allowed_client_shards: Option<ShardSet>,
+    #[serde(default)]
+    pub allow_shard_count_multiples: bool,
+    #[serde(default)]
+    pub compression_self_test: bool,
+    #[serde(default = "default_heartbeat_interval")]
+    pub heartbeat_interval: u64,
+    #[serde(default = "default_heartbeat_jitter")]
+    pub heartbeat_jitter: f64,
+    #[serde(default)]
+    pub bots: HashMap<String, Bot>,
+}
+
+impl Config {
+    /// Check values that can't be validated while deserializing and normalize
+    /// them.
+    fn validate(&mut self) -> Result<(), Error> {
+        // The URL is sent to clients in READY for resuming, so it has to be usable
+        // as a gateway URL. Clients append query parameters to it, which is why
+        // trailing slashes are removed.
+        let url = self.externally_accessible_url.trim_end_matches('/');
+        let is_valid = url.parse::<Uri>().is_ok_and(|uri| {
+            matches!(uri.scheme_str(), Some("ws" | "wss")) && uri.authority().is_some()
+        });
+
+        if !is_valid {
+            return Err(Error::InvalidUrl(self.externally_accessible_url.clone()));
+        }
+
+        self.externally_accessible_url = url.to_string();
+
+        // Tokens can be given inline, in a file or in the environment, in that order
+        if self.token.is_empty() {
+            self.token = match (&self.token_file, var("TOKEN")) {
+                (Some(path), _) => read_token(path)?,
+                (None, Ok(token)) => token,
+                (None, Err(_)) => return Err(Error::MissingToken(None)),
+            };
+        }
+
+        for (name, bot) in &mut self.bots {
+            if bot.token.is_empty() {
+                let Some(path) = &bot.token_file else {
+                    return Err(Error::MissingToken(Some(name.clone())));
+                };
+
+                bot.token = read_token(path)?;
+            }
+        }
+
+        // Without trusted proxies, any client could spoof its address
+        if self.trust_x_forwarded_for && self.trusted_proxies.is_empty() {
+            return Err(Error::MissingTrustedProxies);
+        }
+
+        // Addresses of connections are compared in their canonical form
+        for ip in &mut self.trusted_proxies {
+            *ip = ip.to_canonical();
+        }
+
+        // Twilight appends the query string to the gateway URL in the same way
+        if let Some(gateway_url) = &mut self.gateway_url {
+            let url = gateway_url.trim_end_matches('/');
+            let is_valid = url.parse::<Uri>().is_ok_and(|uri| {
+                matches!(uri.scheme_str(), Some("ws" | "wss")) && uri.authority().is_some()
+            });
+
+            if !is_valid {
+                return Err(Error::InvalidGatewayUrl(gateway_url.clone()));
+            }
+
+            *gateway_url = url.to_string();
+        }
+
+        // The HTTP proxy is given as an address without a scheme
+        if let Some(http_proxy) = &self.twilight_http_proxy {
+            if http_proxy.parse::<Authority>().is_err() {
+                return Err(Error::InvalidHttpProxy(http_proxy.clone()));
+            }
+        }
+
+        Ok(())
+    }
+
+    /// The bot configured at the top level of the config.
+    pub fn default_bot(&self) -> Bot {
+        Bot {
+            token: self.token.clone(),
+            token_file: self.token_file.clone(),
+            consumer_tokens: self.consumer_tokens.clone(),
+            intents: self.intents,
+            shards: self.shards,
+            shard_start: self.shard_start,
+            shard_end: self.shard_end,
+        }
+    }
 }
 
+/// Connection settings for a bot proxied by this process.
 #[derive(Deserialize, Clone)]
+pub struct Bot {
+    #[serde(default)]
+    pub token: String,
+    #[serde(default)]
+    pub token_file: Option<String>,
+    /// Tokens that clients may identify with instead of the bot token, by the
+    /// name of the consumer they are for.
+    #[serde(default)]
+    pub consumer_tokens: HashMap<String, String>,
+    #[serde(deserialize_with = "deserialize_intents")]
+    pub intents: Intents,
+    #[serde(default)]
+    pub shards: Option<u32>,
+    #[serde(default)]
+    pub shard_start: Option<u32>,
+    #[serde(default)]
+    pub shard_end: Option<u32>,
+}
+
+/// What to do with a client that falls behind on a shard's event broadcast.
+#[derive(Deserialize, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum SlowClientPolicy {
+    /// Log a warning and skip the events the client missed.
+    #[default]
+    Warn,
+    /// Disconnect the client so that it reconnects with a fresh session.
+    Disconnect,
+    /// Skip the oldest events queued for the client until it has caught up
+    /// with the threshold, so that it gets the most recent events.
+    DropOldest,
+}
+
+/// What to do when a client updates the voice state in a guild whose voice
+/// connection is owned by another client on the same shard.
+#[derive(Deserialize, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum VoiceStatePolicy {
+    /// Log a warning and send the update to Discord anyways.
+    #[default]
+    Warn,
+    /// Log a warning and drop the update.
+    Reject,
+}
+
+/// A set of shard IDs, either listed or as a range (start inclusive, end
+/// exclusive).
+#[derive(Deserialize, Clone)]
+#[serde(untagged)]
+pub enum ShardSet {
+    List(Vec<u32>),
+    Range { start: u32, end: u32 },
+}
+
+impl ShardSet {
+    pub fn contains(&self, shard_id: u32) -> bool {
+        match self {
+            Self::List(shards) => shards.contains(&shard_id),
+            Self::Range { start, end } => (*start..*end).contains(&shard_id),
+        }
+    }
+}
+
+/// A presence that the shards switch to for a while.
+#[derive(Deserialize, Clone)]
+pub struct RotatingPresence {
+    pub activity: Activity,
+    #[serde(default = "default_status")]
+    pub status: Status,
+    pub duration_secs: u64,
+}
+
+/// Limit for commands that a single client can send to Discord.
+#[derive(Deserialize, Clone)]
+pub struct ClientRatelimit {
+    #[serde(default = "default_ratelimit_commands")]
+    pub commands: u32,
+    #[serde(default = "default_ratelimit_interval_ms")]
+    pub interval_ms: u64,
+}
+
+#[derive(Deserialize, Serialize, Clone)]
 pub struct Cache {
     pub channels: bool,
     pub presences: bool,
@@ -62,6 +335,49 @@ pub struct Cache {
     pub stickers: bool,
     pub users: bool,
     pub voice_states: bool,
+    /// Cache messages and their reactions. Messages are only cached so that
+    /// reactions can be attached to them.
+    #[serde(default)]
+    pub reactions: bool,
+    /// Amount of messages cached per channel if `reactions` is enabled.
+    #[serde(default = "default_message_cache_size")]
+    pub message_cache_size: usize,
+}
+
+impl Cache {
+    /// Amount of messages that the cache keeps per channel.
+    pub const fn message_cache_size(&self) -> usize {
+        if self.reactions {
+            self.message_cache_size
+        } else {
+            0
+        }
+    }
+}
+
+/// What the `GUILD_CREATE`s replayed to a client contain, selected with the
+/// `cache_profile` query string parameter. The cache itself is not affected.
+#[derive(Deserialize, Serialize, Clone)]
+#[serde(default)]
+pub struct CacheProfile {
+    pub members: bool,
+    pub presences: bool,
+    pub voice_states: bool,
+}
+
+impl CacheProfile {
+    /// Profile of clients that didn't select one, which replays everything.
+    pub const ALL: Self = Self {
+        members: true,
+        presences: true,
+        voice_states: true,
+    };
+}
+
+impl Default for CacheProfile {
+    fn default() -> Self {
+        Self::ALL
+    }
 }
 
 impl Default for Cache {
@@ -78,6 +394,8 @@ impl Default for Cache {
             stickers: false,
             users: false,
             voice_states: false,
+            reactions: false,
+            message_cache_size: default_message_cache_size(),
         }
     }
 }
@@ -91,7 +409,8 @@ impl From<Cache> for EventTypeFlags {
             | Self::GATEWAY_INVALIDATE_SESSION;
 
         if cache.members || cache.current_member {
-            flags |= Self::MEMBER_ADD | Self::MEMBER_REMOVE | Self::MEMBER_UPDATE;
+            flags |=
+                Self::MEMBER_ADD | Self::MEMBER_CHUNK | Self::MEMBER_REMOVE | Self::MEMBER_UPDATE;
         }
 
         if cache.roles {
@@ -134,6 +453,17 @@ impl From<Cache> for EventTypeFlags {
             flags |= Self::USER_UPDATE;
         }
 
+        if cache.reactions {
+            flags |= Self::MESSAGE_CREATE
+                | Self::MESSAGE_DELETE
+                | Self::MESSAGE_DELETE_BULK
+                | Self::MESSAGE_UPDATE
+                | Self::REACTION_ADD
+                | Self::REACTION_REMOVE
+                | Self::REACTION_REMOVE_ALL
+                | Self::REACTION_REMOVE_EMOJI;
+        }
+
         flags
     }
 }
@@ -186,10 +516,66 @@ impl From<Cache> for ResourceType {
             resource_types |= Self::VOICE_STATE;
         }
 
+        if cache.reactions {
+            resource_types |= Self::MESSAGE | Self::REACTION;
+        }
+
         resource_types
     }
 }
 
+/// Deserialize intents either from their bitfield or from a list of intent
+/// names, such as `["GUILDS", "GUILD_MESSAGES"]`.
+fn deserialize_intents<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Intents, D::Error> {
+    struct IntentsVisitor;
+
+    impl<'de> Visitor<'de> for IntentsVisitor {
+        type Value = Intents;
+
+        fn expecting(&self, f: &mut Formatter) -> FmtResult {
+            f.write_str("an intents bitfield or a list of intent names")
+        }
+
+        fn visit_u64<E: DeError>(self, value: u64) -> Result<Self::Value, E> {
+            Ok(Intents::from_bits_truncate(value))
+        }
+
+        fn visit_i64<E: DeError>(self, value: i64) -> Result<Self::Value, E> {
+            u64::try_from(value)
+                .map(Intents::from_bits_truncate)
+                .map_err(|_| DeError::custom(format!("invalid intents bitfield {value}")))
+        }
+
+        fn visit_seq<A: SeqAccess<'de>>(self, mut seq: A) -> Result<Self::Value, A::Error> {
+            let mut intents = Intents::empty();
+
+            while let Some(name) = seq.next_element::<String>()? {
+                intents |= Intents::from_name(&name)
+                    .ok_or_else(|| DeError::custom(format!("unknown intent {name}")))?;
+            }
+
+            Ok(intents)
+        }
+    }
+
+    deserializer.deserialize_any(IntentsVisitor)
+}
+
+/// Deserialize event type flags from a list of the names that Discord sends
+/// the events with, such as `["MESSAGE_CREATE", "MESSAGE_REACTION_ADD"]`.
+fn deserialize_event_types<'de, D: Deserializer<'de>>(
+    deserializer: D,
+) -> Result<EventTypeFlags, D::Error> {
+    Vec::<String>::deserialize(deserializer)?.iter().try_fold(
+        EventTypeFlags::empty(),
+        |flags, name| {
+            EventType::try_from(name.as_str())
+                .map(|event_type| flags | EventTypeFlags::from(event_type))
+                .map_err(|_| DeError::custom(format!("unknown event type {name}")))
+        },
+    )
+}
+
 fn default_log_level() -> String {
     String::from("info")
 }
@@ -198,13 +584,11 @@ const fn default_port() -> u16 {
     7878
 }
 
-fn token_fallback() -> String {
-    if let Ok(token) = var("TOKEN") {
-        token
-    } else {
-        eprintln!("Config Error: token is not present and TOKEN environment variable is not set");
-        exit(1);
-    }
+/// Read a token from a file, such as a secret mounted by a secret manager.
+fn read_token(path: &str) -> Result<String, Error> {
+    let token = read_to_string(path).map_err(|_| Error::NotFound(path.to_string()))?;
+
+    Ok(token.trim().to_string())
 }
 
 const fn default_status() -> Status {
@@ -219,8 +603,49 @@ const fn default_validate_token() -> bool {
     true
 }
 
+const fn default_message_cache_size() -> usize {
+    100
+}
+
+const fn default_identify_timeout_ms() -> u64 {
+    5000
+}
+
+const fn default_session_timeout_ms() -> u64 {
+    300_000
+}
+
+const fn default_max_message_size() -> usize {
+    4096
+}
+
+const fn default_compression_buffer_size() -> usize {
+    32 * 1024
+}
+
+const fn default_heartbeat_interval() -> u64 {
+    41250
+}
+
+const fn default_heartbeat_jitter() -> f64 {
+    0.05
+}
+
+const fn default_ratelimit_commands() -> u32 {
+    120
+}
+
+const fn default_ratelimit_interval_ms() -> u64 {
+    60000
+}
+
 pub enum Error {
     InvalidConfig(JsonError),
+    InvalidGatewayUrl(String),
+    InvalidHttpProxy(String),
+    InvalidUrl(String),
+    MissingToken(Option<String>),
+    MissingTrustedProxies,
     NotFound(String),
 }
 
@@ -228,6 +653,24 @@ impl Display for Error {
     fn fmt(&self, f: &mut Formatter<'_>) -> FmtResult {
         match self {
             Self::InvalidConfig(s) => s.fmt(f),
+            Self::InvalidGatewayUrl(s) => f.write_fmt(format_args!(
+                "gateway_url {s} is not a valid ws:// or wss:// URL"
+            )),
+            Self::InvalidHttpProxy(s) => f.write_fmt(format_args!(
+                "twilight_http_proxy {s} is not a valid host:port address"
+            )),
+            Self::InvalidUrl(s) => f.write_fmt(format_args!(
+                "externally_accessible_url {s} is not a valid ws:// or wss:// URL"
+            )),
+            Self::MissingToken(None) => f.write_str(
+                "token is not present, set token, token_file or the TOKEN environment variable (in this order of precedence)",
+            ),
+            Self::MissingToken(Some(name)) => f.write_fmt(format_args!(
+                "token of bot {name} is not present, set token or token_file (in this order of precedence)"
+            )),
+            Self::MissingTrustedProxies => f.write_str(
+                "trust_x_forwarded_for is enabled, but no trusted_proxies are set",
+            ),
             Self::NotFound(s) => f.write_fmt(format_args!("File {s} not found or access denied")),
         }
     }
@@ -236,7 +679,9 @@ impl Display for Error {
 #[cfg(feature = "simd-json")]
 pub fn load(path: &str) -> Result<Config, Error> {
     let mut content = read_to_string(path).map_err(|_| Error::NotFound(path.to_string()))?;
-    let config = unsafe { simd_json::from_str(&mut content) }.map_err(Error::InvalidConfig)?;
+    let mut config: Config =
+        unsafe { simd_json::from_str(&mut content) }.map_err(Error::InvalidConfig)?;
+    config.validate()?;
 
     Ok(config)
 }
@@ -244,11 +689,21 @@ pub fn load(path: &str) -> Result<Config, Error> {
 #[cfg(not(feature = "simd-json"))]
 pub fn load(path: &str) -> Result<Config, Error> {
     let content = read_to_string(path).map_err(|_| Error::NotFound(path.to_string()))?;
-    let config = serde_json::from_str(&content).map_err(Error::InvalidConfig)?;
+    let mut config: Config = serde_json::from_str(&content).map_err(Error::InvalidConfig)?;
+    config.validate()?;
 
     Ok(config)
 }
 
+/// Load the config again from the same file as at startup.
+pub fn load_from_env() -> Result<Config, Error> {
+    let config_path = var("CONFIG");
+    let config_path = config_path.as_deref().unwrap_or("config.json");
+
+    load(config_path)
+}
+
+#[cfg(not(test))]
 pub static CONFIG: LazyLock<Config> = LazyLock::new(|| {
     let config_path = var("CONFIG");
     let config_path = config_path.as_deref().unwrap_or("config.json");
@@ -263,6 +718,23 @@ pub static CONFIG: LazyLock<Config> = LazyLock::new(|| {
     }
 });
 
+/// Config that tests run with instead of reading a file, everything that isn't
+/// required is left at its default.
+#[cfg(test)]
+const TEST_CONFIG: &str =
+    r#"{"token":"test","intents":0,"externally_accessible_url":"ws://localhost:7878"}"#;
+
+#[cfg(test)]
+pub static CONFIG: LazyLock<Config> = LazyLock::new(|| {
+    #[cfg(feature = "simd-json")]
+    let mut config: Config = unsafe { simd_json::from_str(&mut TEST_CONFIG.to_owned()) }.unwrap();
+    #[cfg(not(feature = "simd-json"))]
+    let mut config: Config = serde_json::from_str(TEST_CONFIG).unwrap();
+    config.validate().unwrap();
+
+    config
+});
+
 pub async fn watch_config_changes<S>(reload_handle: reload::Handle<LevelFilter, S>) {
     let Ok(inotify) = Inotify::init() else {
         tracing::error!("Failed to initialize inotify, log-levels cannot be reloaded on the fly");
@@ -299,3 +771,30 @@ pub async fn watch_config_changes<S>(reload_handle: reload::Handle<LevelFilter,
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use serde::de::value::{Error, SeqDeserializer};
+    use twilight_gateway::EventTypeFlags;
+
+    use super::deserialize_event_types;
+
+    fn event_types(names: &[&'static str]) -> Result<EventTypeFlags, Error> {
+        deserialize_event_types(SeqDeserializer::new(names.iter().copied()))
+    }
+
+    #[test]
+    fn parses_gateway_event_names() {
+        // Twilight names the flag of MESSAGE_REACTION_ADD just REACTION_ADD
+        assert_eq!(
+            event_types(&["MESSAGE_CREATE", "MESSAGE_REACTION_ADD"]).unwrap(),
+            EventTypeFlags::MESSAGE_CREATE | EventTypeFlags::REACTION_ADD
+        );
+    }
+
+    #[test]
+    fn rejects_unknown_event_names() {
+        assert!(event_types(&["REACTION_ADD"]).is_err());
+        assert!(event_types(&["NOT_AN_EVENT"]).is_err());
+    }
+}
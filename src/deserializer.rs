@@ -15,6 +15,8 @@
 /// OF USE, DATA OR PROFITS, WHETHER IN AN ACTION OF CONTRACT, NEGLIGENCE OR OTHER
 /// TORTIOUS ACTION, ARISING OUT OF OR IN CONNECTION WITH THE USE OR PERFORMANCE
 /// OF THIS SOFTWARE.
+use twilight_model::id::{marker::GuildMarker, Id};
+
 use std::{ops::Range, str::FromStr};
 
 #[derive(Clone, Debug, Eq, PartialEq)]
@@ -117,3 +119,122 @@ impl<'a> GatewayEvent<'a> {
         T::from_str(clean).ok().map(|int| (int, range))
     }
 }
+
+/// Find the ID of the guild that a dispatch event is for, without parsing the
+/// payload. Events that aren't for a guild, such as direct messages, have none.
+pub fn find_guild_id(input: &str, event_name: &str) -> Option<Id<GuildMarker>> {
+    let data = find_value(input, "d")?;
+
+    // The data of these events is the guild itself
+    let key = match event_name {
+        "GUILD_CREATE" | "GUILD_UPDATE" | "GUILD_DELETE" => "id",
+        _ => "guild_id",
+    };
+
+    let id = find_value(data, key)?.strip_prefix('"')?;
+    let id = &id[..id.find('"')?];
+
+    id.parse().ok().and_then(Id::new_checked)
+}
+
+/// Find the value of a key in a JSON object, returning the rest of the object
+/// starting at the value. Only keys of the object itself are matched, not
+/// those of objects nested in it.
+fn find_value<'a>(object: &'a str, key: &str) -> Option<&'a str> {
+    let bytes = object.as_bytes();
+    let mut depth = 0_u32;
+    let mut index = 0;
+
+    while let Some(&byte) = bytes.get(index) {
+        match byte {
+            b'"' => {
+                let start = index + 1;
+                index = start;
+
+                // Skip to the end of the string, escaped quotes don't end it
+                while let Some(&byte) = bytes.get(index) {
+                    match byte {
+                        b'"' => break,
+                        b'\\' => index += 2,
+                        _ => index += 1,
+                    }
+                }
+
+                // Keys are the strings in the object followed by a colon
+                if depth == 1 && object.get(start..index) == Some(key) {
+                    let rest = object.get(index + 1..)?.trim_start();
+
+                    if let Some(value) = rest.strip_prefix(':') {
+                        return Some(value.trim_start());
+                    }
+                }
+            }
+            b'{' | b'[' => depth += 1,
+            b'}' | b']' if depth <= 1 => return None,
+            b'}' | b']' => depth -= 1,
+            _ => {}
+        }
+
+        index += 1;
+    }
+
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use twilight_model::id::Id;
+
+    use super::{find_guild_id, find_value};
+
+    #[test]
+    fn finds_value_of_top_level_key() {
+        let object = r#"{"a": {"b": 1}, "b" : 2}"#;
+
+        assert_eq!(find_value(object, "b"), Some("2}"));
+        assert_eq!(find_value(object, "c"), None);
+    }
+
+    #[test]
+    fn skips_escaped_quotes_in_strings() {
+        let payload = r#"{"d":{"content":"\"guild_id\":\"1\" \\","guild_id":"2"}}"#;
+
+        assert_eq!(find_guild_id(payload, "MESSAGE_CREATE"), Some(Id::new(2)));
+    }
+
+    #[test]
+    fn ignores_nested_guild_id() {
+        let nested = r#"{"d":{"member":{"guild_id":"1"}}}"#;
+        let both = r#"{"d":{"member":{"guild_id":"1"},"guild_id":"2"}}"#;
+
+        assert_eq!(find_guild_id(nested, "TYPING_START"), None);
+        assert_eq!(find_guild_id(both, "TYPING_START"), Some(Id::new(2)));
+    }
+
+    #[test]
+    fn ignores_null_guild_id() {
+        let payload = r#"{"d":{"guild_id": null,"channel_id":"1"}}"#;
+
+        assert_eq!(find_guild_id(payload, "MESSAGE_CREATE"), None);
+    }
+
+    #[test]
+    fn uses_id_for_guild_events() {
+        let payload = r#"{"d":{"owner_id":"1","id":"2"}}"#;
+
+        assert_eq!(find_guild_id(payload, "GUILD_CREATE"), Some(Id::new(2)));
+        assert_eq!(find_guild_id(payload, "GUILD_UPDATE"), Some(Id::new(2)));
+        assert_eq!(find_guild_id(payload, "GUILD_DELETE"), Some(Id::new(2)));
+        assert_eq!(find_guild_id(payload, "MESSAGE_CREATE"), None);
+    }
+
+    #[test]
+    fn ignores_data_that_is_not_an_object() {
+        assert_eq!(find_guild_id(r#"{"d":null}"#, "MESSAGE_CREATE"), None);
+        assert_eq!(find_guild_id(r#"{"d":"guild_id"}"#, "MESSAGE_CREATE"), None);
+        assert_eq!(
+            find_guild_id(r#"{"d":[{"guild_id":"1"}]}"#, "MESSAGE_CREATE"),
+            None
+        );
+    }
+}
@@ -1,48 +1,109 @@
 use futures_util::StreamExt;
 use itoa::Buffer;
+use serde::de::DeserializeOwned;
+#[cfg(not(feature = "simd-json"))]
+use serde_json::to_string;
 #[cfg(feature = "simd-json")]
-use simd_json::prelude::ValueAsMutContainer;
-use tokio::{sync::broadcast, time::Instant};
-use tracing::{debug, trace};
+use simd_json::{prelude::ValueAsMutContainer, to_string};
+use tokio::{
+    sync::{
+        mpsc::{unbounded_channel, UnboundedReceiver, UnboundedSender},
+        watch,
+    },
+    time::{sleep, timeout, Instant},
+};
+use tracing::{debug, info, trace};
 use twilight_gateway::{
-    parse, Event, EventTypeFlags, Message, Shard, ShardState as ConnectionState,
+    parse, CloseFrame, ConfigBuilder, Event, EventTypeFlags, Message, Shard,
+    ShardState as ConnectionState,
+};
+use twilight_model::{
+    gateway::{
+        event::GatewayEvent as TwilightGatewayEvent,
+        payload::{incoming::GuildCreate, outgoing::RequestGuildMembers},
+        CloseCode, Intents,
+    },
+    id::{marker::GuildMarker, Id},
 };
-use twilight_model::gateway::event::GatewayEvent as TwilightGatewayEvent;
 
 use std::{
+    collections::VecDeque,
+    mem,
     sync::{atomic::Ordering, Arc},
     time::Duration,
 };
 
 use crate::{
     config::CONFIG,
-    deserializer::{EventTypeInfo, GatewayEvent, SequenceInfo},
-    model::Ready,
+    deserializer::{self, EventTypeInfo, GatewayEvent, SequenceInfo},
+    intents,
+    model::{Ready, VoiceEvent},
     state::Shard as ShardState,
     SHUTDOWN,
 };
 
-pub type BroadcastMessage = (String, Option<SequenceInfo>);
+/// A message sent to all clients of a shard.
+#[derive(Clone)]
+pub enum BroadcastMessage {
+    /// An event to relay to the clients.
+    Event(BroadcastEvent),
+    /// Send the clients a new READY built from the cache, so that they rebuild
+    /// their state.
+    Resync,
+    /// Close the connections of the clients because the proxy shuts down.
+    Shutdown,
+    /// Close the connections of the clients because the bot's token changed
+    /// and they have to identify with the new one.
+    TokenChanged,
+    /// Close the connections of the clients because Discord closed the shard
+    /// with this code, which it can't recover from.
+    Failed(CloseCode),
+}
+
+/// An event sent to the clients of a shard.
+#[derive(Clone)]
+pub struct BroadcastEvent {
+    pub payload: String,
+    pub sequence: Option<SequenceInfo>,
+    /// Session that the event is only for, if any.
+    pub session_id: Option<String>,
+    /// Intents that the event is sent for, if clients are filtered by intents.
+    pub intents: Option<Intents>,
+    /// Guild that the event is for, if any, so that clients can be filtered by
+    /// it without parsing the payload again.
+    pub guild_id: Option<Id<GuildMarker>>,
+    /// When the event was received from Discord.
+    pub received_at: Instant,
+    /// Position of the event in the shard's history.
+    pub index: u64,
+}
 
 const TEN_SECONDS: Duration = Duration::from_secs(10);
 
+/// Time to wait for Discord to acknowledge the close of a shard before it is
+/// replaced anyway.
+const CLOSE_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Time between member chunk requests sent by the proxy itself.
+const CHUNK_REQUEST_INTERVAL: Duration = Duration::from_millis(500);
+
+/// Nonce of member chunk requests sent by the proxy. Responses to these are not
+/// relayed to clients because they did not ask for them.
+const CHUNK_NONCE: &str = "gateway-proxy";
+
+/// [`CHUNK_NONCE`] as it appears in the responses.
+const CHUNK_NONCE_FIELD: &str = r#""nonce":"gateway-proxy""#;
+
 pub async fn events(
     mut shard: Shard,
     shard_state: Arc<ShardState>,
-    shard_id: u32,
-    broadcast_tx: broadcast::Sender<BroadcastMessage>,
+    mut token: watch::Receiver<String>,
 ) {
-    // This method only wants to relay events while the shard is in a READY state
-    // Therefore, we only put events in the queue while we are connected and READY
-    let mut is_ready = false;
-
-    let mut buffer = Buffer::new();
-    let shard_id_str = buffer.format(shard_id).to_owned();
+    let shard_id = shard_state.id;
+    let mut dispatcher = Dispatcher::new(shard_state);
 
     let mut last_metrics_update = Instant::now();
 
-    let event_type_flags: EventTypeFlags = CONFIG.cache.clone().into();
-
     loop {
         // Update metrics if the last update was more than 10s ago
         let now = Instant::now();
@@ -50,100 +111,565 @@ pub async fn events(
         if now.duration_since(last_metrics_update) > TEN_SECONDS {
             let latencies = shard.latency().recent();
             let info = shard.state();
-            update_shard_statistics(&shard_id_str, &shard_state, info, latencies);
+            update_shard_statistics(
+                &dispatcher.shard_id_str,
+                &dispatcher.shard_state,
+                info,
+                latencies,
+            );
             last_metrics_update = now;
         }
 
-        let payload = match shard.next().await {
+        let message = tokio::select! {
+            message = shard.next() => message,
+            Ok(()) = token.changed() => {
+                // Twilight can't change the token of a shard, so it is replaced
+                // by a new one that identifies with the new token
+                info!("[Shard {shard_id}] Token changed, identifying again");
+                close(&mut shard, &mut dispatcher).await;
+                shard = with_token(&shard, token.borrow_and_update().clone());
+                dispatcher.shard_state.sender.replace(shard.sender());
+                dispatcher.shard_state.ready.set_not_ready();
+                // The new token might be what the shard failed with
+                *dispatcher.shard_state.failed.lock().unwrap() = None;
+
+                continue;
+            }
+        };
+
+        let payload = match message {
             Some(Ok(Message::Text(payload))) => payload,
             Some(Ok(Message::Close(_))) if SHUTDOWN.load(Ordering::Relaxed) => return,
-            Some(Ok(Message::Close(_))) => {
-                tracing::info!("Shard {shard_id} got a close message");
+            Some(Ok(Message::Close(Some(frame))))
+                if frame.code == CloseCode::DisallowedIntents as u16 =>
+            {
+                // Discord doesn't say which intent it rejected, but it can only be a
+                // privileged one that the bot isn't approved for
+                let privileged = shard.config().intents()
+                    & (Intents::GUILD_MEMBERS
+                        | Intents::GUILD_PRESENCES
+                        | Intents::MESSAGE_CONTENT);
+                let names = privileged
+                    .iter_names()
+                    .map(|(name, _)| name)
+                    .collect::<Vec<_>>()
+                    .join(", ");
+
+                tracing::error!("[Shard {shard_id}] Discord rejected the privileged intents ({names}), enable them for the bot in the developer portal or remove them from the config");
+                dispatcher
+                    .shard_state
+                    .set_last_error(format!("Disallowed intents ({names})"));
+                dispatcher.shard_state.set_close_code(frame.code);
+                dispatcher.closed(frame.code);
+                metrics::counter!("gateway_shard_privileged_intent_errors", "bot" => dispatcher.shard_state.bot.clone(), "shard" => dispatcher.shard_id_str.clone())
+                    .increment(1);
+
+                continue;
+            }
+            Some(Ok(Message::Close(frame))) => {
+                tracing::info!("[Shard {shard_id}] Got a close message");
+
+                if let Some(frame) = &frame {
+                    dispatcher.shard_state.set_close_code(frame.code);
+                }
+
+                // Codes from 4000 are Discord's, the others are regular closes
+                if let Some(frame) = frame.filter(|frame| frame.code >= 4000) {
+                    let meaning = close_code_meaning(frame.code);
+                    tracing::warn!(
+                        "[Shard {shard_id}] Closed by Discord with code {} ({meaning}): {}",
+                        frame.code,
+                        frame.reason
+                    );
+                    dispatcher.shard_state.set_last_error(format!(
+                        "Closed by Discord with code {} ({meaning}): {}",
+                        frame.code, frame.reason
+                    ));
+                    dispatcher.closed(frame.code);
+                }
 
                 continue;
             }
             Some(Err(e)) => {
-                tracing::error!("Error receiving message: {e}");
+                tracing::error!("[Shard {shard_id}] Error receiving message: {e}");
+                dispatcher.shard_state.set_last_error(e.to_string());
                 continue;
             }
             None => {
-                tracing::warn!("Shard {shard_id} stream closed");
+                tracing::warn!("[Shard {shard_id}] Stream closed");
                 return;
             }
         };
 
+        dispatcher.handle(payload);
+    }
+}
+
+/// Name of a close code sent by Discord, such as "Authentication Failed".
+pub fn close_code_meaning(code: u16) -> String {
+    CloseCode::try_from(code).map_or_else(|_| String::from("Unknown"), |code| code.to_string())
+}
+
+/// Close the connection of a shard to Discord and wait until it is closed, so
+/// that its session doesn't linger next to the one of the shard replacing it.
+/// Payloads received until then are still dispatched.
+async fn close(shard: &mut Shard, dispatcher: &mut Dispatcher) {
+    let shard_id = dispatcher.shard_state.id;
+    shard.close(CloseFrame::NORMAL);
+
+    let closed = timeout(CLOSE_TIMEOUT, async {
+        while let Some(message) = shard.next().await {
+            match message {
+                Ok(Message::Text(payload)) => dispatcher.handle(payload),
+                Ok(Message::Close(_)) => return,
+                Err(_) => {}
+            }
+        }
+    })
+    .await;
+
+    if closed.is_err() {
+        tracing::warn!("[Shard {shard_id}] Connection did not close in time, replacing it anyway");
+    }
+}
+
+/// Create a shard with the same config as another one, but a new token.
+fn with_token(shard: &Shard, token: String) -> Shard {
+    let config = shard.config();
+    let mut builder = ConfigBuilder::new(token, config.intents()).queue(config.queue().clone());
+
+    if let Some(presence) = config.presence() {
+        builder = builder.presence(presence.clone());
+    }
+
+    if let Some(gateway_url) = config.proxy_url() {
+        builder = builder.proxy_url(gateway_url.to_owned());
+    }
+
+    Shard::with_config(shard.id(), builder.build())
+}
+
+/// Buffers for parsing the payloads that the dispatcher looks into itself.
+/// simd-json needs a mutable copy of the payload and scratch space, which are
+/// reused instead of allocating them for every payload.
+#[derive(Default)]
+struct Scratch {
+    #[cfg(feature = "simd-json")]
+    input: Vec<u8>,
+    #[cfg(feature = "simd-json")]
+    buffers: simd_json::Buffers,
+}
+
+impl Scratch {
+    #[cfg(feature = "simd-json")]
+    fn parse<T: DeserializeOwned>(&mut self, payload: &str) -> simd_json::Result<T> {
+        self.input.clear();
+        self.input.extend_from_slice(payload.as_bytes());
+
+        simd_json::serde::from_slice_with_buffers(&mut self.input, &mut self.buffers)
+    }
+
+    #[cfg(not(feature = "simd-json"))]
+    #[allow(clippy::unused_self, clippy::needless_pass_by_ref_mut)]
+    fn parse<T: DeserializeOwned>(&mut self, payload: &str) -> serde_json::Result<T> {
+        serde_json::from_str(payload)
+    }
+}
+
+/// Processes the payloads received by a shard: keeps track of its READY state,
+/// broadcasts events to clients and updates the cache.
+///
+/// This is independent of the shard's connection, so any source of payloads
+/// can drive it.
+pub struct Dispatcher {
+    shard_state: Arc<ShardState>,
+    shard_id_str: String,
+    event_type_flags: EventTypeFlags,
+    /// Guilds are queued up for chunking as they become available after READY.
+    chunk_tx: Option<UnboundedSender<Id<GuildMarker>>>,
+    /// Events are only relayed while the shard is connected and READY.
+    is_ready: bool,
+    /// Whether a READY was received before. Clients have to be resynced when
+    /// the shard gets a new session.
+    had_ready: bool,
+    /// When the dispatcher was created, right before the shard connects.
+    created_at: Instant,
+    /// Events received while the shard resumes its session, relayed in order
+    /// once it has resumed.
+    buffered: VecDeque<BufferedEvent>,
+    /// Whether events were dropped because the buffer was full.
+    buffer_overflowed: bool,
+    scratch: Scratch,
+}
+
+/// A dispatch event held back until the shard has resumed.
+struct BufferedEvent {
+    payload: String,
+    sequence: Option<SequenceInfo>,
+    event_name: String,
+    received_at: Instant,
+}
+
+impl Dispatcher {
+    pub fn new(shard_state: Arc<ShardState>) -> Self {
+        let mut buffer = Buffer::new();
+        let shard_id_str = buffer.format(shard_state.id).to_owned();
+
+        let chunk_tx = CONFIG.chunk_guilds_on_ready.then(|| {
+            let (chunk_tx, chunk_rx) = unbounded_channel();
+            tokio::spawn(request_members(shard_state.clone(), chunk_rx));
+            chunk_tx
+        });
+
+        // Gateway control events are tiny and parsed in addition to the cached ones
+        // so that they can be logged
+        let event_type_flags = EventTypeFlags::from(CONFIG.cache.clone())
+            | CONFIG.event_types
+            | EventTypeFlags::GATEWAY_HELLO
+            | EventTypeFlags::GATEWAY_HEARTBEAT
+            | EventTypeFlags::GATEWAY_HEARTBEAT_ACK
+            | EventTypeFlags::GATEWAY_RECONNECT;
+
+        Self {
+            shard_state,
+            shard_id_str,
+            event_type_flags,
+            chunk_tx,
+            is_ready: false,
+            had_ready: false,
+            created_at: Instant::now(),
+            buffered: VecDeque::new(),
+            buffer_overflowed: false,
+            scratch: Scratch::default(),
+        }
+    }
+
+    /// Process a payload received from Discord.
+    pub fn handle(&mut self, payload: String) {
+        let shard_id = self.shard_state.id;
+        let received_at = Instant::now();
+
         // NOTE: payload cannot be modified because we have to do optional event parsing
         // later. Don't use simd_json::from_str on it because that will make the data useless.
         // Instead, clone it before mutating.
         let Some(event) = GatewayEvent::from_json(&payload) else {
-            tracing::error!("Failed to deserialize gateway event");
-            continue;
+            tracing::error!("[Shard {shard_id}] Failed to deserialize gateway event");
+            return;
         };
 
         let (op, sequence, event_type) = event.into_parts();
 
         if let Some(EventTypeInfo(event_name, _)) = event_type {
-            metrics::counter!("gateway_shard_events", "shard" => shard_id_str.clone(), "event_type" => event_name.to_owned()).increment(1);
+            self.relay(&payload, op.0, sequence, event_name, received_at);
+        }
 
-            if event_name == "READY" {
-                // Use the raw JSON from READY to create a new blank READY
+        if let Ok(Some(event)) = parse(payload, self.event_type_flags) {
+            self.update(event);
+        }
+    }
 
-                #[cfg(feature = "simd-json")]
-                let mut ready: Ready =
-                    unsafe { simd_json::from_str(&mut payload.clone()).unwrap() };
-                #[cfg(not(feature = "simd-json"))]
-                let mut ready: Ready = serde_json::from_str(&payload).unwrap();
+    /// Keep track of READY and RESUMED and broadcast dispatch events to clients.
+    fn relay(
+        &mut self,
+        payload: &str,
+        op: u8,
+        sequence: Option<SequenceInfo>,
+        event_name: &str,
+        received_at: Instant,
+    ) {
+        let shard_id = self.shard_state.id;
+        let shard_state = &self.shard_state;
 
-                // Clear the guilds
-                if let Some(guilds) = ready.d.get_mut("guilds") {
-                    if let Some(arr) = guilds.as_array_mut() {
-                        arr.clear();
-                    }
+        metrics::counter!("gateway_shard_events", "bot" => shard_state.bot.clone(), "shard" => self.shard_id_str.clone(), "event_type" => event_name.to_owned()).increment(1);
+
+        if event_name == "READY" {
+            // Use the raw JSON from READY to create a new blank READY
+
+            let mut ready: Ready = self.scratch.parse(payload).unwrap();
+
+            // Override resume_gateway_url with the external URI of the proxy
+            ready.d.insert(
+                String::from("resume_gateway_url"),
+                CONFIG.externally_accessible_url.clone().into(),
+            );
+
+            let first =
+                (CONFIG.first_client_real_ready && !self.had_ready).then(|| ready.d.clone());
+
+            // Clear the guilds
+            if let Some(guilds) = ready.d.get_mut("guilds") {
+                if let Some(arr) = guilds.as_array_mut() {
+                    arr.clear();
                 }
+            }
 
-                // Override resume_gateway_url with the external URI of the proxy
-                ready.d.insert(
-                    String::from("resume_gateway_url"),
-                    CONFIG.externally_accessible_url.clone().into(),
-                );
+            // We don't care if it was already set
+            // since this data is timeless
+            shard_state.ready.set_ready(ready.d, first);
+            self.is_ready = true;
 
-                // We don't care if it was already set
-                // since this data is timeless
-                shard_state.ready.set_ready(ready.d);
-                is_ready = true;
-            } else if event_name == "RESUMED" {
-                is_ready = true;
-            } else if op.0 == 0 && is_ready {
-                // We only want to relay dispatchable events, not RESUMEs and not READY
-                // because we fake a READY event
-                let payload_copy = payload.clone();
-                trace!("[Shard {shard_id}] Sending payload to clients: {payload_copy:?}",);
+            // Clients still have the state of the previous session
+            if self.had_ready {
+                debug!("[Shard {shard_id}] Got a new session, resyncing clients");
+                let _res = shard_state.events.send(BroadcastMessage::Resync);
+            } else {
+                let time_to_ready = self.created_at.elapsed();
+                info!("[Shard {shard_id}] Ready after {time_to_ready:?}");
+                metrics::gauge!("gateway_shard_time_to_ready_seconds", "bot" => shard_state.bot.clone(), "shard" => self.shard_id_str.clone())
+                    .set(time_to_ready.as_secs_f64());
+            }
 
-                let _res = broadcast_tx.send((payload_copy, sequence));
+            self.had_ready = true;
+            self.discard_buffered("not_ready");
+            // Clients get a READY made by the proxy instead
+            self.count_dropped(event_name, "ready");
+        } else if event_name == "RESUMED" {
+            self.is_ready = true;
+            self.flush_buffered();
+            self.count_dropped(event_name, "resumed");
+        } else if event_name == "GUILD_MEMBERS_CHUNK" && is_own_chunk(payload) {
+            trace!("[Shard {shard_id}] Not relaying member chunk requested by the proxy");
+            self.count_dropped(event_name, "own_chunk");
+        } else if CONFIG.drop_events.iter().any(|name| name == event_name) {
+            trace!("[Shard {shard_id}] Dropping {event_name} event");
+            self.count_dropped(event_name, "drop_events");
+        } else if op == 0 && self.is_ready {
+            // We only want to relay dispatchable events, not RESUMEs and not READY
+            // because we fake a READY event
+            self.broadcast(payload, sequence, event_name, received_at);
+        } else if op == 0 && self.had_ready && CONFIG.reconnect_buffer_size > 0 {
+            // The session is resuming, so the clients' state is still valid once
+            // the shard has caught up
+            if self.buffered.len() < CONFIG.reconnect_buffer_size {
+                self.buffered.push_back(BufferedEvent {
+                    payload: payload.to_owned(),
+                    sequence,
+                    event_name: event_name.to_owned(),
+                    received_at,
+                });
+            } else {
+                self.buffer_overflowed = true;
+                self.count_dropped(event_name, "reconnect_buffer_full");
             }
+        } else if op == 0 {
+            self.count_dropped(event_name, "not_ready");
+        } else {
+            self.count_dropped(event_name, "not_dispatch");
         }
+    }
 
-        if let Ok(Some(event)) = parse(payload, event_type_flags) {
-            match event {
-                TwilightGatewayEvent::Dispatch(_, event) => {
-                    shard_state.guilds.update(Event::from(event));
-                }
-                TwilightGatewayEvent::InvalidateSession(can_resume) => {
-                    debug!("[Shard {shard_id}] Session invalidated, resumable: {can_resume}");
-                    if !can_resume {
-                        // We can only reset the READY state if we know that we will get a new READY,
-                        // which is the case if we can not resume.
-                        shard_state.ready.set_not_ready();
+    /// Broadcast a dispatch event to the clients of the shard.
+    fn broadcast(
+        &mut self,
+        payload: &str,
+        sequence: Option<SequenceInfo>,
+        event_name: &str,
+        received_at: Instant,
+    ) {
+        let shard_id = self.shard_state.id;
+        let shard_state = &self.shard_state;
+
+        trace!("[Shard {shard_id}] Sending payload to clients: {payload:?}",);
+
+        // Voice connection details are only for the client that owns the voice connection
+        let session_id = match event_name {
+            "VOICE_STATE_UPDATE" | "VOICE_SERVER_UPDATE" => {
+                voice_owner(&mut self.scratch, payload, shard_state)
+            }
+            _ => None,
+        };
+
+        let res = shard_state.events.send(BroadcastMessage::Event(
+            shard_state.history.push(BroadcastEvent {
+                payload: payload.to_owned(),
+                sequence,
+                session_id,
+                intents: CONFIG
+                    .filter_client_intents
+                    .then(|| intents::required_intents(event_name))
+                    .flatten(),
+                guild_id: deserializer::find_guild_id(payload, event_name),
+                received_at,
+                index: 0,
+            }),
+        ));
+
+        // Sending only fails if no client is subscribed to the shard
+        if res.is_ok() {
+            metrics::counter!("gateway_shard_events_forwarded", "bot" => shard_state.bot.clone(), "shard" => self.shard_id_str.clone(), "event_type" => event_name.to_owned()).increment(1);
+        } else {
+            self.count_dropped(event_name, "no_clients");
+        }
+    }
+
+    /// Relay the events buffered while the shard resumed. If some of them
+    /// didn't fit into the buffer, clients are resynced from the cache instead,
+    /// which is up to date with all events.
+    fn flush_buffered(&mut self) {
+        let shard_id = self.shard_state.id;
+
+        if self.buffer_overflowed {
+            debug!("[Shard {shard_id}] Missed events while resuming, resyncing clients");
+            self.discard_buffered("reconnect_buffer_full");
+            let _res = self.shard_state.events.send(BroadcastMessage::Resync);
+            return;
+        }
+
+        if !self.buffered.is_empty() {
+            debug!(
+                "[Shard {shard_id}] Relaying {} events buffered while resuming",
+                self.buffered.len()
+            );
+        }
+
+        for event in mem::take(&mut self.buffered) {
+            self.broadcast(
+                &event.payload,
+                event.sequence,
+                &event.event_name,
+                event.received_at,
+            );
+        }
+    }
+
+    /// Drop the buffered events, for example because a new session replaces
+    /// the one they belong to.
+    fn discard_buffered(&mut self, reason: &'static str) {
+        for event in mem::take(&mut self.buffered) {
+            self.count_dropped(&event.event_name, reason);
+        }
+
+        self.buffer_overflowed = false;
+    }
+
+    /// Fail the shard if Discord closed it with a code that reconnecting won't
+    /// help with, such as an invalid token or disallowed intents.
+    fn closed(&self, code: u16) {
+        let shard_id = self.shard_state.id;
+
+        if let Ok(close_code) = CloseCode::try_from(code) {
+            if !close_code.can_reconnect() {
+                tracing::error!("[Shard {shard_id}] Closed with fatal code {code} ({close_code}), disconnecting its clients");
+                self.shard_state.set_failed(close_code);
+            }
+        }
+    }
+
+    /// Count an event that was intentionally not relayed to clients.
+    fn count_dropped(&self, event_name: &str, reason: &'static str) {
+        metrics::counter!("gateway_shard_events_dropped", "bot" => self.shard_state.bot.clone(), "shard" => self.shard_id_str.clone(), "event_type" => event_name.to_owned(), "reason" => reason).increment(1);
+    }
+
+    /// Update the cache and the session state with a parsed event.
+    fn update(&mut self, event: TwilightGatewayEvent) {
+        let shard_id = self.shard_state.id;
+        let shard_state = &self.shard_state;
+
+        match event {
+            TwilightGatewayEvent::Dispatch(_, event) => {
+                let event = Event::from(event);
+
+                let available_guild_id = match &event {
+                    Event::GuildCreate(guild_create) => match guild_create.as_ref() {
+                        GuildCreate::Available(guild) => Some(guild.id),
+                        GuildCreate::Unavailable(_) => None,
+                    },
+                    _ => None,
+                };
+
+                let changes_guilds = matches!(event, Event::GuildCreate(_) | Event::GuildDelete(_));
+
+                shard_state.guilds.update(event);
+
+                // Guilds are checked once the GUILD_CREATE is cached, so that guilds
+                // whose members are all cached already aren't chunked again, for
+                // example after the shard identified again
+                if let (Some(chunk_tx), Some(guild_id)) = (&self.chunk_tx, available_guild_id) {
+                    if !shard_state.guilds.has_all_members(guild_id) {
+                        let _res = chunk_tx.send(guild_id);
                     }
-                    // Suspend sending events to clients until READY or RESUMED are received.
-                    is_ready = false;
                 }
-                _ => {}
+
+                // Keep the guild count live instead of waiting for the next statistics update
+                if changes_guilds {
+                    metrics::gauge!("gateway_cache_guilds", "bot" => shard_state.bot.clone(), "shard" => self.shard_id_str.clone())
+                        .set(shard_state.guilds.stats().guilds() as f64);
+                }
+            }
+            TwilightGatewayEvent::InvalidateSession(can_resume) => {
+                debug!("[Shard {shard_id}] Session invalidated, resumable: {can_resume}");
+                if !can_resume {
+                    // We can only reset the READY state if we know that we will get a new READY,
+                    // which is the case if we can not resume.
+                    shard_state.ready.set_not_ready();
+                }
+                // Suspend sending events to clients until READY or RESUMED are received.
+                self.is_ready = false;
+            }
+            TwilightGatewayEvent::Reconnect => {
+                debug!("[Shard {shard_id}] Gateway requested a reconnect");
+            }
+            TwilightGatewayEvent::Hello(hello) => {
+                debug!(
+                    "[Shard {shard_id}] Received HELLO with a heartbeat interval of {}ms",
+                    hello.heartbeat_interval
+                );
+            }
+            TwilightGatewayEvent::Heartbeat(_) => {
+                trace!("[Shard {shard_id}] Gateway requested a heartbeat");
+            }
+            TwilightGatewayEvent::HeartbeatAck => {
+                trace!("[Shard {shard_id}] Received heartbeat ACK");
             }
         }
     }
 }
 
+/// Request the members of all guilds sent on the channel, spaced out to not
+/// exceed the gateway ratelimit for clients sending commands at the same time.
+async fn request_members(
+    shard_state: Arc<ShardState>,
+    mut guild_rx: UnboundedReceiver<Id<GuildMarker>>,
+) {
+    let shard_id = shard_state.id;
+
+    while let Some(guild_id) = guild_rx.recv().await {
+        debug!("[Shard {shard_id}] Requesting members for guild {guild_id}");
+
+        let request = RequestGuildMembers::builder(guild_id)
+            .nonce(CHUNK_NONCE)
+            .query("", None);
+
+        if let Ok(command) = to_string(&request) {
+            shard_state.commands.send(command);
+        }
+
+        sleep(CHUNK_REQUEST_INTERVAL).await;
+    }
+}
+
+/// Find the session owning the voice connection that a `VOICE_SERVER_UPDATE`
+/// or a `VOICE_STATE_UPDATE` of the bot itself is for.
+fn voice_owner(scratch: &mut Scratch, payload: &str, shard_state: &ShardState) -> Option<String> {
+    let event: VoiceEvent = scratch.parse(payload).ok()?;
+
+    // Voice states of other users are relevant to every client
+    if event
+        .d
+        .user_id
+        .is_some_and(|user_id| Some(user_id) != shard_state.guilds.current_user_id())
+    {
+        return None;
+    }
+
+    shard_state.voice_owners.owner(event.d.guild_id?)
+}
+
+/// Whether a `GUILD_MEMBERS_CHUNK` payload is a response to a request of the proxy.
+fn is_own_chunk(payload: &str) -> bool {
+    payload.contains(CHUNK_NONCE_FIELD)
+}
+
 pub fn update_shard_statistics(
     shard_id: &str,
     shard_state: &Arc<ShardState>,
@@ -161,34 +687,92 @@ pub fn update_shard_statistics(
 
     let latency = latencies.first().map_or(f64::NAN, Duration::as_secs_f64);
 
-    metrics::histogram!("gateway_shard_latency_histogram", "shard" => shard_id.to_string())
+    metrics::histogram!("gateway_shard_latency_histogram", "bot" => shard_state.bot.clone(), "shard" => shard_id.to_string())
         .record(latency);
     metrics::gauge!(
         "gateway_shard_latency",
-        "shard" => shard_id.to_string()
+        "bot" => shard_state.bot.clone(), "shard" => shard_id.to_string()
     )
     .set(latency);
-    metrics::histogram!("gateway_shard_status", "shard" => shard_id.to_string())
+    metrics::histogram!("gateway_shard_status", "bot" => shard_state.bot.clone(), "shard" => shard_id.to_string())
         .record(connection_status);
 
+    // Events are retained in the broadcast until every client has received them
+    metrics::gauge!("gateway_shard_broadcast_depth", "bot" => shard_state.bot.clone(), "shard" => shard_id.to_string())
+        .set(shard_state.events.len() as f64);
+    metrics::gauge!("gateway_shard_subscribers", "bot" => shard_state.bot.clone(), "shard" => shard_id.to_string())
+        .set(shard_state.events.receiver_count() as f64);
+
     let stats = shard_state.guilds.stats();
 
-    metrics::gauge!("gateway_cache_emojis", "shard" => shard_id.to_string())
+    metrics::gauge!("gateway_cache_emojis", "bot" => shard_state.bot.clone(), "shard" => shard_id.to_string())
         .set(stats.emojis() as f64);
-    metrics::gauge!("gateway_cache_guilds", "shard" => shard_id.to_string())
+    metrics::gauge!("gateway_cache_guilds", "bot" => shard_state.bot.clone(), "shard" => shard_id.to_string())
         .set(stats.guilds() as f64);
-    metrics::gauge!("gateway_cache_members", "shard" => shard_id.to_string())
+    metrics::gauge!("gateway_cache_members", "bot" => shard_state.bot.clone(), "shard" => shard_id.to_string())
         .set(stats.members() as f64);
-    metrics::gauge!("gateway_cache_presences", "shard" => shard_id.to_string())
+    metrics::gauge!("gateway_cache_presences", "bot" => shard_state.bot.clone(), "shard" => shard_id.to_string())
         .set(stats.presences() as f64);
-    metrics::gauge!("gateway_cache_channels", "shard" => shard_id.to_string())
+    metrics::gauge!("gateway_cache_channels", "bot" => shard_state.bot.clone(), "shard" => shard_id.to_string())
         .set(stats.channels() as f64);
-    metrics::gauge!("gateway_cache_roles", "shard" => shard_id.to_string())
+    metrics::gauge!("gateway_cache_roles", "bot" => shard_state.bot.clone(), "shard" => shard_id.to_string())
         .set(stats.roles() as f64);
-    metrics::gauge!("gateway_cache_unavailable_guilds", "shard" => shard_id.to_string())
+    metrics::gauge!("gateway_cache_unavailable_guilds", "bot" => shard_state.bot.clone(), "shard" => shard_id.to_string())
         .set(stats.unavailable_guilds() as f64);
-    metrics::gauge!("gateway_cache_users", "shard" => shard_id.to_string())
+    metrics::gauge!("gateway_cache_users", "bot" => shard_state.bot.clone(), "shard" => shard_id.to_string())
         .set(stats.users() as f64);
-    metrics::gauge!("gateway_cache_voice_states", "shard" => shard_id.to_string())
+    metrics::gauge!("gateway_cache_voice_states", "bot" => shard_state.bot.clone(), "shard" => shard_id.to_string())
         .set(stats.voice_states() as f64);
 }
+
+#[cfg(test)]
+mod tests {
+    use tokio::sync::broadcast::error::TryRecvError;
+    use twilight_model::id::Id;
+
+    use std::sync::Arc;
+
+    use super::{BroadcastMessage, Dispatcher};
+    use crate::{deserializer::SequenceInfo, state::Shard};
+
+    const READY: &str =
+        r#"{"t":"READY","s":1,"op":0,"d":{"v":10,"session_id":"session","guilds":[]}}"#;
+    const MESSAGE_CREATE: &str =
+        r#"{"t":"MESSAGE_CREATE","s":2,"op":0,"d":{"id":"1","guild_id":"3","content":"hi"}}"#;
+
+    #[tokio::test]
+    async fn broadcasts_dispatches_after_ready() {
+        let shard = Arc::new(Shard::disconnected(0));
+        let mut events = shard.events.subscribe();
+        let mut dispatcher = Dispatcher::new(shard.clone());
+
+        dispatcher.handle(READY.to_owned());
+        assert!(shard.ready.is_ready());
+
+        dispatcher.handle(MESSAGE_CREATE.to_owned());
+
+        let Ok(BroadcastMessage::Event(event)) = events.try_recv() else {
+            panic!("MESSAGE_CREATE was not broadcast");
+        };
+
+        assert_eq!(event.payload, MESSAGE_CREATE);
+        assert_eq!(event.sequence.map(|SequenceInfo(seq, _)| seq), Some(2));
+        assert_eq!(event.guild_id.map(Id::get), Some(3));
+        assert_eq!(event.session_id, None);
+
+        // Clients get a READY from the proxy instead of Discord's
+        assert!(matches!(events.try_recv(), Err(TryRecvError::Empty)));
+    }
+
+    #[tokio::test]
+    async fn drops_dispatches_before_ready() {
+        let shard = Arc::new(Shard::disconnected(0));
+        let mut events = shard.events.subscribe();
+        let mut dispatcher = Dispatcher::new(shard.clone());
+
+        dispatcher.handle(MESSAGE_CREATE.to_owned());
+
+        assert!(!shard.ready.is_ready());
+        assert!(matches!(events.try_recv(), Err(TryRecvError::Empty)));
+    }
+}
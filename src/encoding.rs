@@ -0,0 +1,40 @@
+use tokio_websockets::Message;
+
+/// Payload encoding used for a client connection.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Encoding {
+    /// JSON, as used by Discord unless ETF is requested.
+    #[default]
+    Json,
+}
+
+impl Encoding {
+    /// Parse the value of the `encoding` query string parameter. ETF is not
+    /// supported, because the proxy relays the JSON payloads it receives from
+    /// Discord as they are.
+    pub fn from_query(value: &str) -> Option<Self> {
+        match value {
+            "json" => Some(Self::Json),
+            _ => None,
+        }
+    }
+
+    /// Name of the encoding as used in the query string.
+    pub const fn name(self) -> &'static str {
+        match self {
+            Self::Json => "json",
+        }
+    }
+
+    /// Decode a payload sent by a client to JSON, so that it can be handled
+    /// the same way regardless of the encoding. Returns `None` if the message
+    /// isn't valid in this encoding.
+    pub fn decode(self, message: &Message) -> Option<&str> {
+        match self {
+            // Some clients send JSON in binary frames, which Discord accepts
+            Self::Json => message
+                .as_text()
+                .or_else(|| std::str::from_utf8(message.as_payload()).ok()),
+        }
+    }
+}
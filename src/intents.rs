@@ -0,0 +1,301 @@
+use twilight_model::gateway::Intents;
+
+use crate::config::Cache;
+
+/// Dispatch events and the intents that Discord sends them for, as documented in
+/// <https://discord.com/developers/docs/topics/gateway#list-of-intents>.
+///
+/// Events that are sent for more than one intent, usually one for guilds and
+/// one for DMs, are sent if any of them is enabled. Events not listed here are
+/// sent regardless of intents.
+const EVENT_INTENTS: &[(&str, Intents)] = &[
+    ("GUILD_CREATE", Intents::GUILDS),
+    ("GUILD_UPDATE", Intents::GUILDS),
+    ("GUILD_DELETE", Intents::GUILDS),
+    ("GUILD_ROLE_CREATE", Intents::GUILDS),
+    ("GUILD_ROLE_UPDATE", Intents::GUILDS),
+    ("GUILD_ROLE_DELETE", Intents::GUILDS),
+    ("CHANNEL_CREATE", Intents::GUILDS),
+    ("CHANNEL_UPDATE", Intents::GUILDS),
+    ("CHANNEL_DELETE", Intents::GUILDS),
+    (
+        "CHANNEL_PINS_UPDATE",
+        Intents::GUILDS.union(Intents::DIRECT_MESSAGES),
+    ),
+    ("THREAD_CREATE", Intents::GUILDS),
+    ("THREAD_UPDATE", Intents::GUILDS),
+    ("THREAD_DELETE", Intents::GUILDS),
+    ("THREAD_LIST_SYNC", Intents::GUILDS),
+    ("THREAD_MEMBER_UPDATE", Intents::GUILDS),
+    (
+        "THREAD_MEMBERS_UPDATE",
+        Intents::GUILDS.union(Intents::GUILD_MEMBERS),
+    ),
+    ("STAGE_INSTANCE_CREATE", Intents::GUILDS),
+    ("STAGE_INSTANCE_UPDATE", Intents::GUILDS),
+    ("STAGE_INSTANCE_DELETE", Intents::GUILDS),
+    ("GUILD_MEMBER_ADD", Intents::GUILD_MEMBERS),
+    ("GUILD_MEMBER_UPDATE", Intents::GUILD_MEMBERS),
+    ("GUILD_MEMBER_REMOVE", Intents::GUILD_MEMBERS),
+    ("GUILD_AUDIT_LOG_ENTRY_CREATE", Intents::GUILD_MODERATION),
+    ("GUILD_BAN_ADD", Intents::GUILD_MODERATION),
+    ("GUILD_BAN_REMOVE", Intents::GUILD_MODERATION),
+    ("GUILD_EMOJIS_UPDATE", Intents::GUILD_EMOJIS_AND_STICKERS),
+    ("GUILD_STICKERS_UPDATE", Intents::GUILD_EMOJIS_AND_STICKERS),
+    (
+        "GUILD_SOUNDBOARD_SOUND_CREATE",
+        Intents::GUILD_EMOJIS_AND_STICKERS,
+    ),
+    (
+        "GUILD_SOUNDBOARD_SOUND_UPDATE",
+        Intents::GUILD_EMOJIS_AND_STICKERS,
+    ),
+    (
+        "GUILD_SOUNDBOARD_SOUND_DELETE",
+        Intents::GUILD_EMOJIS_AND_STICKERS,
+    ),
+    (
+        "GUILD_SOUNDBOARD_SOUNDS_UPDATE",
+        Intents::GUILD_EMOJIS_AND_STICKERS,
+    ),
+    ("GUILD_INTEGRATIONS_UPDATE", Intents::GUILD_INTEGRATIONS),
+    ("INTEGRATION_CREATE", Intents::GUILD_INTEGRATIONS),
+    ("INTEGRATION_UPDATE", Intents::GUILD_INTEGRATIONS),
+    ("INTEGRATION_DELETE", Intents::GUILD_INTEGRATIONS),
+    ("WEBHOOKS_UPDATE", Intents::GUILD_WEBHOOKS),
+    ("INVITE_CREATE", Intents::GUILD_INVITES),
+    ("INVITE_DELETE", Intents::GUILD_INVITES),
+    ("VOICE_CHANNEL_EFFECT_SEND", Intents::GUILD_VOICE_STATES),
+    ("VOICE_STATE_UPDATE", Intents::GUILD_VOICE_STATES),
+    ("PRESENCE_UPDATE", Intents::GUILD_PRESENCES),
+    (
+        "MESSAGE_CREATE",
+        Intents::GUILD_MESSAGES.union(Intents::DIRECT_MESSAGES),
+    ),
+    (
+        "MESSAGE_UPDATE",
+        Intents::GUILD_MESSAGES.union(Intents::DIRECT_MESSAGES),
+    ),
+    (
+        "MESSAGE_DELETE",
+        Intents::GUILD_MESSAGES.union(Intents::DIRECT_MESSAGES),
+    ),
+    ("MESSAGE_DELETE_BULK", Intents::GUILD_MESSAGES),
+    (
+        "MESSAGE_REACTION_ADD",
+        Intents::GUILD_MESSAGE_REACTIONS.union(Intents::DIRECT_MESSAGE_REACTIONS),
+    ),
+    (
+        "MESSAGE_REACTION_REMOVE",
+        Intents::GUILD_MESSAGE_REACTIONS.union(Intents::DIRECT_MESSAGE_REACTIONS),
+    ),
+    (
+        "MESSAGE_REACTION_REMOVE_ALL",
+        Intents::GUILD_MESSAGE_REACTIONS.union(Intents::DIRECT_MESSAGE_REACTIONS),
+    ),
+    (
+        "MESSAGE_REACTION_REMOVE_EMOJI",
+        Intents::GUILD_MESSAGE_REACTIONS.union(Intents::DIRECT_MESSAGE_REACTIONS),
+    ),
+    (
+        "TYPING_START",
+        Intents::GUILD_MESSAGE_TYPING.union(Intents::DIRECT_MESSAGE_TYPING),
+    ),
+    (
+        "GUILD_SCHEDULED_EVENT_CREATE",
+        Intents::GUILD_SCHEDULED_EVENTS,
+    ),
+    (
+        "GUILD_SCHEDULED_EVENT_UPDATE",
+        Intents::GUILD_SCHEDULED_EVENTS,
+    ),
+    (
+        "GUILD_SCHEDULED_EVENT_DELETE",
+        Intents::GUILD_SCHEDULED_EVENTS,
+    ),
+    (
+        "GUILD_SCHEDULED_EVENT_USER_ADD",
+        Intents::GUILD_SCHEDULED_EVENTS,
+    ),
+    (
+        "GUILD_SCHEDULED_EVENT_USER_REMOVE",
+        Intents::GUILD_SCHEDULED_EVENTS,
+    ),
+    (
+        "AUTO_MODERATION_RULE_CREATE",
+        Intents::AUTO_MODERATION_CONFIGURATION,
+    ),
+    (
+        "AUTO_MODERATION_RULE_UPDATE",
+        Intents::AUTO_MODERATION_CONFIGURATION,
+    ),
+    (
+        "AUTO_MODERATION_RULE_DELETE",
+        Intents::AUTO_MODERATION_CONFIGURATION,
+    ),
+    (
+        "AUTO_MODERATION_ACTION_EXECUTION",
+        Intents::AUTO_MODERATION_EXECUTION,
+    ),
+    (
+        "MESSAGE_POLL_VOTE_ADD",
+        Intents::GUILD_MESSAGE_POLLS.union(Intents::DIRECT_MESSAGE_POLLS),
+    ),
+    (
+        "MESSAGE_POLL_VOTE_REMOVE",
+        Intents::GUILD_MESSAGE_POLLS.union(Intents::DIRECT_MESSAGE_POLLS),
+    ),
+];
+
+/// Get the intents that an event is sent for, or `None` if Discord sends it
+/// regardless of intents.
+pub fn required_intents(event_name: &str) -> Option<Intents> {
+    EVENT_INTENTS
+        .iter()
+        .find(|(name, _)| *name == event_name)
+        .map(|(_, intents)| *intents)
+}
+
+/// Whether Discord would send an event with these intents to a client.
+pub fn is_sent_for(required: Option<Intents>, intents: Intents) -> bool {
+    required.is_none_or(|required| intents.intersects(required))
+}
+
+/// Find the cache flags that are enabled without the intent that their
+/// resources are received with, as the flag and the name of the intent.
+pub fn missing_for_cache(cache: &Cache, intents: Intents) -> Vec<(&'static str, &'static str)> {
+    [
+        ("channels", cache.channels, Intents::GUILDS, "GUILDS"),
+        (
+            "current_member",
+            cache.current_member,
+            Intents::GUILDS,
+            "GUILDS",
+        ),
+        (
+            "emojis",
+            cache.emojis,
+            Intents::GUILD_EMOJIS_AND_STICKERS,
+            "GUILD_EMOJIS_AND_STICKERS",
+        ),
+        (
+            "members",
+            cache.members,
+            Intents::GUILD_MEMBERS,
+            "GUILD_MEMBERS",
+        ),
+        (
+            "presences",
+            cache.presences,
+            Intents::GUILD_PRESENCES,
+            "GUILD_PRESENCES",
+        ),
+        // Reactions are attached to cached messages
+        (
+            "reactions",
+            cache.reactions,
+            Intents::GUILD_MESSAGES,
+            "GUILD_MESSAGES",
+        ),
+        (
+            "reactions",
+            cache.reactions,
+            Intents::GUILD_MESSAGE_REACTIONS,
+            "GUILD_MESSAGE_REACTIONS",
+        ),
+        ("roles", cache.roles, Intents::GUILDS, "GUILDS"),
+        (
+            "scheduled_events",
+            cache.scheduled_events,
+            Intents::GUILD_SCHEDULED_EVENTS,
+            "GUILD_SCHEDULED_EVENTS",
+        ),
+        (
+            "stage_instances",
+            cache.stage_instances,
+            Intents::GUILDS,
+            "GUILDS",
+        ),
+        (
+            "stickers",
+            cache.stickers,
+            Intents::GUILD_EMOJIS_AND_STICKERS,
+            "GUILD_EMOJIS_AND_STICKERS",
+        ),
+        (
+            "voice_states",
+            cache.voice_states,
+            Intents::GUILD_VOICE_STATES,
+            "GUILD_VOICE_STATES",
+        ),
+    ]
+    .into_iter()
+    .filter(|(_, enabled, required, _)| *enabled && !intents.contains(*required))
+    .map(|(flag, _, _, intent)| (flag, intent))
+    .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use twilight_model::gateway::Intents;
+
+    use super::{is_sent_for, missing_for_cache, required_intents};
+    use crate::config::Cache;
+
+    #[test]
+    fn looks_up_required_intents() {
+        assert_eq!(
+            required_intents("MESSAGE_CREATE"),
+            Some(Intents::GUILD_MESSAGES | Intents::DIRECT_MESSAGES)
+        );
+        assert_eq!(
+            required_intents("PRESENCE_UPDATE"),
+            Some(Intents::GUILD_PRESENCES)
+        );
+        assert_eq!(required_intents("READY"), None);
+        assert_eq!(required_intents("NOT_AN_EVENT"), None);
+    }
+
+    #[test]
+    fn sends_event_for_any_required_intent() {
+        let required = required_intents("MESSAGE_CREATE");
+
+        assert!(is_sent_for(required, Intents::DIRECT_MESSAGES));
+        assert!(!is_sent_for(required, Intents::GUILDS));
+        assert!(is_sent_for(None, Intents::empty()));
+    }
+
+    #[test]
+    fn finds_cache_flags_missing_intents() {
+        let cache = Cache {
+            members: true,
+            ..Cache::default()
+        };
+
+        assert_eq!(
+            missing_for_cache(&cache, Intents::GUILDS),
+            [("members", "GUILD_MEMBERS")]
+        );
+        assert!(missing_for_cache(&cache, Intents::GUILDS | Intents::GUILD_MEMBERS).is_empty());
+    }
+
+    #[test]
+    fn requires_message_intents_for_reactions() {
+        let cache = Cache {
+            reactions: true,
+            ..Cache::default()
+        };
+
+        assert_eq!(
+            missing_for_cache(&cache, Intents::GUILDS),
+            [
+                ("reactions", "GUILD_MESSAGES"),
+                ("reactions", "GUILD_MESSAGE_REACTIONS")
+            ]
+        );
+        assert_eq!(
+            missing_for_cache(&cache, Intents::GUILDS | Intents::GUILD_MESSAGE_REACTIONS),
+            [("reactions", "GUILD_MESSAGES")]
+        );
+    }
+}
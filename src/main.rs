@@ -11,18 +11,18 @@ use metrics_exporter_prometheus::PrometheusBuilder;
 use mimalloc::MiMalloc;
 use tokio::{
     signal::unix::{signal, SignalKind},
-    sync::broadcast,
+    sync::{broadcast, watch, Notify},
     task::JoinSet,
-    time::timeout,
+    time::{sleep, timeout},
 };
-use tracing::{debug, error, info};
+use tracing::{debug, error, info, warn};
 use tracing_subscriber::{
     filter::LevelFilter, layer::SubscriberExt, reload, util::SubscriberInitExt,
 };
 use twilight_cache_inmemory::InMemoryCache;
 use twilight_gateway::{CloseFrame, ConfigBuilder, Shard, ShardId};
 use twilight_gateway_queue::InMemoryQueue;
-use twilight_http::Client;
+use twilight_http::{error::ErrorType, response::StatusCode, Client, Error as HttpError};
 use twilight_model::gateway::payload::outgoing::update_presence::UpdatePresencePayload;
 
 use std::{
@@ -31,18 +31,29 @@ use std::{
     str::FromStr,
     sync::{
         atomic::{AtomicBool, Ordering},
-        Arc, RwLock,
+        Arc, Mutex, RwLock,
     },
     time::Duration,
 };
 
-use crate::config::CONFIG;
+use crate::{
+    config::{Bot, CONFIG},
+    state::State,
+};
 
+mod admin;
 mod cache;
+mod compression;
 mod config;
 mod deserializer;
 mod dispatch;
+mod encoding;
+mod intents;
 mod model;
+mod presence;
+#[cfg(feature = "pprof")]
+mod profiling;
+mod ratelimit;
 mod server;
 mod state;
 mod upgrade;
@@ -52,6 +63,12 @@ static GLOBAL: MiMalloc = MiMalloc;
 
 static SHUTDOWN: AtomicBool = AtomicBool::new(false);
 
+/// Notified when a shutdown is requested through the admin API.
+static SHUTDOWN_REQUESTED: Notify = Notify::const_new();
+
+/// Name of the bot configured at the top level of the config.
+const DEFAULT_BOT: &str = "default";
+
 #[allow(
     clippy::cognitive_complexity,
     clippy::too_many_lines,
@@ -68,24 +85,231 @@ async fn run() -> Result<(), Box<dyn Error + Send + Sync>> {
 
     tokio::spawn(config::watch_config_changes(reload_handle));
 
+    if CONFIG.compression_self_test {
+        compression::self_test().map_err(|e| format!("Compression self-test failed: {e}"))?;
+        debug!("Compression self-test passed");
+    }
+
     // Set up metrics collection
     let metrics_handle = PrometheusBuilder::new().install_recorder().unwrap();
 
-    // Set up a HTTPClient
-    let mut client_builder = Client::builder().token(CONFIG.token.clone());
+    if let Some(max_clients) = CONFIG.max_clients {
+        metrics::gauge!("gateway_clients_max").set(max_clients as f64);
+    }
+
+    let mut dispatch_tasks = JoinSet::new();
+
+    let default = start_bot(
+        String::from(DEFAULT_BOT),
+        CONFIG.default_bot(),
+        &mut dispatch_tasks,
+    )
+    .await?;
+
+    let mut bots = HashMap::with_capacity(CONFIG.bots.len());
+
+    for (name, bot) in &CONFIG.bots {
+        let state = start_bot(name.clone(), bot.clone(), &mut dispatch_tasks).await?;
+        bots.insert(name.clone(), state);
+    }
+
+    let proxy = Arc::new(state::Proxy { default, bots });
+
+    let proxy_clone = proxy.clone();
+    tokio::spawn(async move {
+        proxy_clone.wait_until_ready().await;
+        info!("All shards are ready");
+    });
+
+    if !CONFIG.presence_rotation.is_empty() {
+        tokio::spawn(presence::rotate(proxy.clone()));
+    }
+
+    tokio::spawn(reload_tokens(proxy.clone()));
+
+    if let Some(metrics_port) = CONFIG.metrics_port {
+        tokio::spawn(server::run_metrics(
+            metrics_port,
+            proxy.clone(),
+            metrics_handle.clone(),
+        ));
+    }
+
+    let proxy_clone = proxy.clone();
+    tokio::spawn(async move {
+        if let Err(e) = server::run(CONFIG.port, proxy_clone, metrics_handle).await {
+            error!("{}", e);
+        }
+    });
+
+    let mut sigint = signal(SignalKind::interrupt()).unwrap();
+    let mut sigterm = signal(SignalKind::terminate()).unwrap();
+
+    tokio::select! {
+        _ = sigint.recv() => info!("received SIGINT, shutting down"),
+        _ = sigterm.recv() => info!("received SIGTERM, shutting down"),
+        () = SHUTDOWN_REQUESTED.notified() => info!("shutdown requested, shutting down"),
+    }
+
+    // Set the flag so that event handlers will be able to tell that a GatewayClose is an expected shutdown,
+    // this also stops accepting new clients
+    SHUTDOWN.store(true, Ordering::Relaxed);
+
+    // Disconnect the clients, they can connect again once the proxy is back
+    for shard in proxy.iter().flat_map(|state| &state.shards) {
+        let _res = shard.events.send(dispatch::BroadcastMessage::Shutdown);
+    }
+
+    // Initiate the shutdown for all shards
+    for shard in proxy.iter().flat_map(|state| &state.shards) {
+        let _ = shard.sender.close(CloseFrame::NORMAL);
+    }
+
+    let mut graceful = 0;
+    let mut ungraceful = dispatch_tasks.len();
+
+    // Wait for all shards to shut down, but if we for some reason fail to do so, exit anyways
+    info!("waiting for {ungraceful} active shard dispatching tasks to shut down");
+
+    loop {
+        match timeout(Duration::from_secs(10), dispatch_tasks.join_next()).await {
+            Ok(Some(_)) => {
+                debug!("shard dispatching task shut down");
+                graceful += 1;
+                ungraceful -= 1;
+            } // Shard task shut down
+            Ok(None) => break, // Set is empty, all tasks were graceful
+            Err(_) => {
+                error!("no shard shut down within 10 seconds, force quitting");
+                break;
+            } // No shard shut down in 10s, remaining ones are ungraceful
+        }
+    }
+
+    info!("{graceful} shards shut down gracefully, {ungraceful} not gracefully");
+
+    Ok(())
+}
+
+/// Reload the tokens from the config on SIGHUP, so that they can be rotated
+/// without a restart.
+async fn reload_tokens(proxy: Arc<state::Proxy>) {
+    let Ok(mut sighup) = signal(SignalKind::hangup()) else {
+        error!("failed to listen for SIGHUP, tokens can't be reloaded");
+        return;
+    };
+
+    while sighup.recv().await.is_some() {
+        let config = match config::load_from_env() {
+            Ok(config) => config,
+            Err(e) => {
+                error!("received SIGHUP, but failed to reload the config: {e}");
+                continue;
+            }
+        };
+
+        info!("received SIGHUP, reloaded tokens");
+        rotate_token(&proxy.default, config.token);
+
+        for (name, bot) in config.bots {
+            if let Some(state) = proxy.bots.get(&name) {
+                rotate_token(state, bot.token);
+            }
+        }
+    }
+}
+
+/// Switch a bot to a new token. Its shards identify again with it and clients
+/// using the old token are disconnected.
+fn rotate_token(state: &State, token: String) {
+    if state.is_token(&token) {
+        return;
+    }
+
+    info!("[Bot {}] Token changed, reconnecting shards", state.name);
+    state.token.send_replace(token);
+
+    if CONFIG.validate_token {
+        for shard in &state.shards {
+            let _res = shard.events.send(dispatch::BroadcastMessage::TokenChanged);
+        }
+    }
+}
+
+/// Create a HTTP client for a bot. All REST requests have to be made with a
+/// client created here so that they go through `twilight_http_proxy`.
+fn http_client(token: String) -> Client {
+    let mut client_builder = Client::builder().token(token);
 
     if let Some(http_proxy) = CONFIG.twilight_http_proxy.clone() {
         client_builder = client_builder.proxy(http_proxy, true);
     }
 
-    let client = Arc::new(client_builder.build());
+    client_builder.build()
+}
 
-    // Check total shards required
-    let gateway = client.gateway().authed().await?.model().await?;
+fn is_unauthorized(error: &HttpError) -> bool {
+    match error.kind() {
+        ErrorType::Unauthorized => true,
+        ErrorType::Response { status, .. } => *status == StatusCode::UNAUTHORIZED,
+        _ => false,
+    }
+}
+
+/// Connect all shards of a bot and start dispatching their events.
+#[allow(clippy::too_many_lines)]
+async fn start_bot(
+    name: String,
+    bot: Bot,
+    dispatch_tasks: &mut JoinSet<()>,
+) -> Result<State, Box<dyn Error + Send + Sync>> {
+    let client = http_client(bot.token.clone());
+
+    // Check total shards required, which also makes sure that the token is
+    // valid before clients can connect to the proxy
+    let gateway = match client.gateway().authed().await {
+        Ok(response) => response.model().await?,
+        Err(e) if is_unauthorized(&e) => {
+            return Err(
+                format!("[Bot {name}] Discord rejected the token, check your config").into(),
+            );
+        }
+        Err(e) => return Err(e.into()),
+    };
+
+    // Cache flags without their intent leave the cache empty without any error
+    let missing_intents = intents::missing_for_cache(&CONFIG.cache, bot.intents);
+
+    for (flag, intent) in &missing_intents {
+        warn!("[Bot {name}] The {flag} cache flag is enabled, but the {intent} intent is missing, so nothing will be cached for it");
+    }
+
+    if CONFIG.strict_cache_intents && !missing_intents.is_empty() {
+        return Err(format!("[Bot {name}] Cache flags are enabled without their intents").into());
+    }
 
     let session = gateway.session_start_limit;
 
-    let shard_count = CONFIG.shards.unwrap_or(gateway.shards);
+    let shard_count = bot.shards.unwrap_or(gateway.shards);
+
+    info!(
+        "[Bot {name}] Discord recommends {} shards with a max concurrency of {}",
+        gateway.shards, session.max_concurrency
+    );
+
+    if bot.shards.is_some() {
+        info!("[Bot {name}] Using the configured shard count of {shard_count}");
+    } else {
+        info!("[Bot {name}] Using the recommended shard count of {shard_count}");
+    }
+
+    let intent_names = bot
+        .intents
+        .iter_names()
+        .map(|(name, _)| name)
+        .collect::<Vec<_>>()
+        .join(", ");
+    info!("[Bot {name}] Identifying with the intents {intent_names}");
 
     // Set up a queue for the shards
     let queue = InMemoryQueue::new(
@@ -96,25 +320,44 @@ async fn run() -> Result<(), Box<dyn Error + Send + Sync>> {
     );
 
     // Create all shards
-    let shard_start = CONFIG.shard_start.unwrap_or(0);
-    let shard_end = CONFIG.shard_end.unwrap_or(shard_count);
+    let shard_start = bot.shard_start.unwrap_or(0);
+    let shard_end = bot.shard_end.unwrap_or(shard_count);
     let shard_end_inclusive = shard_end - 1;
     let mut shards = Vec::with_capacity((shard_end - shard_start) as usize);
 
-    info!("Creating shards {shard_start} to {shard_end_inclusive} of {shard_count} total",);
+    info!("[Bot {name}] Creating shards {shard_start} to {shard_end_inclusive} of {shard_count} total");
 
-    let config = ConfigBuilder::new(CONFIG.token.clone(), CONFIG.intents)
-        .queue(queue)
-        .build();
+    let mut config = ConfigBuilder::new(bot.token.clone(), bot.intents).queue(queue);
 
-    let mut dispatch_tasks = JoinSet::new();
+    if let Some(gateway_url) = &CONFIG.gateway_url {
+        info!("[Bot {name}] Connecting to the gateway at {gateway_url}");
+        config = config.proxy_url(gateway_url.clone());
+    }
+
+    let config = config.build();
+
+    // Shards are connected in buckets of max_concurrency, which can be spread
+    // out further to avoid a burst of identifies
+    let max_concurrency = u32::from(session.max_concurrency.max(1));
+    let shard_connect_delay = Duration::from_millis(CONFIG.shard_connect_delay_ms);
+
+    if !shard_connect_delay.is_zero() {
+        let buckets = (shard_end - shard_start).div_ceil(max_concurrency);
+        info!(
+            "[Bot {name}] Connecting {max_concurrency} shards every {}ms, which takes {:?}",
+            CONFIG.shard_connect_delay_ms,
+            shard_connect_delay * buckets.saturating_sub(1)
+        );
+    }
+
+    // Shards identify again when the token is changed
+    let token_tx = watch::Sender::new(bot.token.clone());
 
     for shard_id in shard_start..shard_end {
         let mut builder = ConfigBuilder::from(config.clone());
 
-        if let Some(mut activity) = CONFIG.activity.clone() {
-            // Replace {{shard}} with the actual ID
-            activity.name = activity.name.replace("{{shard}}", &shard_id.to_string());
+        if let Some(activity) = &CONFIG.activity {
+            let activity = presence::for_shard(activity, shard_id);
             // Will only error if activities are empty, so we can unwrap
             builder = builder.presence(
                 UpdatePresencePayload::new(vec![activity], false, None, CONFIG.status).unwrap(),
@@ -130,89 +373,113 @@ async fn run() -> Result<(), Box<dyn Error + Send + Sync>> {
         let cache = Arc::new(
             InMemoryCache::builder()
                 .resource_types(CONFIG.cache.clone().into())
-                .message_cache_size(0)
+                .message_cache_size(CONFIG.cache.message_cache_size())
                 .build(),
         );
         let guild_cache = cache::Guilds::new(cache.clone());
 
         let ready = state::Ready::new();
 
+        let sender = Arc::new(state::ShardSender::new(shard.sender()));
+
         let shard_status = Arc::new(state::Shard {
             id: shard_id,
-            sender: shard.sender(),
-            events: broadcast_tx.clone(),
+            bot: name.clone(),
+            sender: sender.clone(),
+            commands: ratelimit::CommandQueue::new(sender, name.clone(), shard_id),
+            events: broadcast_tx,
             ready,
             guilds: guild_cache,
+            voice_owners: state::VoiceOwners::default(),
+            last_error: Mutex::default(),
+            last_close_code: Mutex::default(),
+            failed: Mutex::default(),
+            history: state::EventHistory::new(CONFIG.resume_buffer_size),
         });
 
         // Now pipe the events into the broadcast
         // and handle state updates for the guild cache
         // and set the ready event if received
-        dispatch_tasks.spawn(dispatch::events(
-            shard,
-            shard_status.clone(),
-            shard_id,
-            broadcast_tx,
-        ));
+        let index = shard_id - shard_start;
+        let connect_delay = shard_connect_delay * (index / max_concurrency);
+        let shard_status_clone = shard_status.clone();
+        let token = token_tx.subscribe();
+        let bot_name = name.clone();
+
+        dispatch_tasks.spawn(async move {
+            // Shards only connect once their events are polled
+            if !shard_connect_delay.is_zero() {
+                sleep(connect_delay).await;
+
+                if SHUTDOWN.load(Ordering::Relaxed) {
+                    return;
+                }
+
+                if index.is_multiple_of(max_concurrency) {
+                    info!("[Bot {bot_name}] Connecting shards starting at shard {shard_id}");
+                }
+            }
+
+            dispatch::events(shard, shard_status_clone, token).await;
+        });
 
         shards.push(shard_status);
 
-        debug!("Created shard {shard_id} of {shard_count} total");
+        debug!("[Bot {name}] Created shard {shard_id} of {shard_count} total");
     }
 
     let state = Arc::new(state::Inner {
+        name,
+        token: token_tx,
+        consumer_tokens: bot.consumer_tokens,
+        intents: bot.intents,
         shards,
         shard_count,
+        recommended_shard_count: gateway.shards,
+        max_concurrency: session.max_concurrency,
         sessions: RwLock::new(HashMap::new()),
+        clients: state::Clients::default(),
     });
 
-    let state_clone = state.clone();
-    tokio::spawn(async move {
-        if let Err(e) = server::run(CONFIG.port, state_clone, metrics_handle).await {
-            error!("{}", e);
-        }
-    });
-
-    let mut sigint = signal(SignalKind::interrupt()).unwrap();
-    let mut sigterm = signal(SignalKind::terminate()).unwrap();
-
-    tokio::select! {
-        _ = sigint.recv() => info!("received SIGINT, shutting down"),
-        _ = sigterm.recv() => info!("received SIGTERM, shutting down"),
-    }
-
-    // Set the flag so that event handlers will be able to tell that a GatewayClose is an expected shutdown
-    SHUTDOWN.store(true, Ordering::Relaxed);
+    log_shard_assignment(&state);
 
-    // Initiate the shutdown for all shards
-    for shard in &state.shards {
-        let _ = shard.sender.close(CloseFrame::NORMAL);
-    }
+    Ok(state)
+}
 
-    let mut graceful = 0;
-    let mut ungraceful = dispatch_tasks.len();
+/// Log the shards that the proxy runs for a bot, so that gaps and overlaps are
+/// easy to spot when several proxies split up the shards of a bot.
+fn log_shard_assignment(state: &State) {
+    let shard_ids = state
+        .shards
+        .iter()
+        .map(|shard| shard.id.to_string())
+        .collect::<Vec<_>>()
+        .join(", ");
+
+    info!(
+        "[Bot {}] Running {} of {} shards: {shard_ids}",
+        state.name,
+        state.shards.len(),
+        state.shard_count
+    );
 
-    // Wait for all shards to shut down, but if we for some reason fail to do so, exit anyways
-    info!("waiting for {ungraceful} active shard dispatching tasks to shut down");
+    // Clients are routed to shards by their ID, which has to find the same shard
+    for (index, shard) in state.shards.iter().enumerate() {
+        debug!(
+            "[Bot {}] Local index {index} is shard {}",
+            state.name, shard.id
+        );
 
-    loop {
-        match timeout(Duration::from_secs(10), dispatch_tasks.join_next()).await {
-            Ok(Some(_)) => {
-                debug!("shard dispatching task shut down");
-                graceful += 1;
-                ungraceful -= 1;
-            } // Shard task shut down
-            Ok(None) => break, // Set is empty, all tasks were graceful
-            Err(_) => {
-                error!("no shard shut down within 10 seconds, force quitting");
-                break;
-            } // No shard shut down in 10s, remaining ones are ungraceful
+        if !state
+            .shard(shard.id)
+            .is_some_and(|found| Arc::ptr_eq(&found, shard))
+        {
+            error!(
+                "[Bot {}] Shard {} at local index {index} can't be found by its ID",
+                state.name, shard.id
+            );
         }
     }
-
-    info!("{graceful} shards shut down gracefully, {ungraceful} not gracefully");
-
-    Ok(())
 }
 
 fn main() {
@@ -1,8 +1,15 @@
 use serde::Deserialize;
 #[cfg(not(feature = "simd-json"))]
 use serde_json::Value as OwnedValue;
-#[cfg(feature = "simd-json")]
-use simd_json::OwnedValue;
+use twilight_model::{
+    gateway::payload::outgoing::request_guild_members::RequestGuildMembersInfo,
+    id::{
+        marker::{ChannelMarker, GuildMarker, UserMarker},
+        Id,
+    },
+};
+
+use std::collections::HashSet;
 
 #[derive(Deserialize)]
 pub struct Identify {
@@ -14,14 +21,54 @@ pub struct Resume {
     pub d: ResumeInfo,
 }
 
+#[derive(Deserialize)]
+pub struct RequestGuildMembers {
+    pub d: RequestGuildMembersInfo,
+}
+
+#[derive(Deserialize)]
+pub struct UpdateVoiceState {
+    pub d: UpdateVoiceStateInfo,
+}
+
+#[derive(Deserialize)]
+pub struct VoiceEvent {
+    pub d: VoiceEventInfo,
+}
+
 #[derive(Deserialize)]
 pub struct IdentifyInfo {
     #[serde(default)]
     pub compress: Option<bool>,
-    pub shard: [u32; 2],
+    #[serde(default)]
+    pub intents: Option<u64>,
+    /// Shard ID and shard count, which should be exactly two elements.
+    #[serde(default)]
+    pub shard: Vec<u32>,
+    /// Proxy extension for clients that keep their own guild state.
+    #[serde(default)]
+    pub skip_guild_replay: bool,
+    /// Proxy extension for clients that only want the events of some guilds.
+    #[serde(default)]
+    pub guild_ids: Option<HashSet<Id<GuildMarker>>>,
     pub token: String,
 }
 
+#[derive(Deserialize)]
+pub struct UpdateVoiceStateInfo {
+    pub guild_id: Id<GuildMarker>,
+    pub channel_id: Option<Id<ChannelMarker>>,
+}
+
+/// Fields shared by `VOICE_STATE_UPDATE` and `VOICE_SERVER_UPDATE` events.
+#[derive(Deserialize)]
+pub struct VoiceEventInfo {
+    #[serde(default)]
+    pub guild_id: Option<Id<GuildMarker>>,
+    #[serde(default)]
+    pub user_id: Option<Id<UserMarker>>,
+}
+
 #[derive(Deserialize)]
 pub struct ResumeInfo {
     pub session_id: String,
@@ -34,4 +81,7 @@ pub struct Ready {
     pub d: JsonObject,
 }
 
+#[cfg(feature = "simd-json")]
+pub type JsonObject = simd_json::owned::Object;
+#[cfg(not(feature = "simd-json"))]
 pub type JsonObject = halfbrown::HashMap<String, OwnedValue>;
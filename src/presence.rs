@@ -0,0 +1,47 @@
+#[cfg(not(feature = "simd-json"))]
+use serde_json::to_string;
+#[cfg(feature = "simd-json")]
+use simd_json::to_string;
+use tokio::time::sleep;
+use tracing::debug;
+use twilight_model::gateway::{payload::outgoing::UpdatePresence, presence::Activity};
+
+use std::{sync::Arc, time::Duration};
+
+use crate::{config::CONFIG, state::Proxy};
+
+/// Shortest time that a presence is shown for. Discord historically allowed 5
+/// presence updates per minute, so rotating any faster risks a ratelimit.
+const MIN_DURATION: Duration = Duration::from_secs(12);
+
+/// Replace `{{shard}}` in the name of an activity with the ID of a shard.
+pub fn for_shard(activity: &Activity, shard_id: u32) -> Activity {
+    let mut activity = activity.clone();
+    activity.name = activity.name.replace("{{shard}}", &shard_id.to_string());
+
+    activity
+}
+
+/// Cycle through the presences in `presence_rotation` on all shards.
+pub async fn rotate(proxy: Arc<Proxy>) {
+    // Presences can only be updated once the shards are connected
+    proxy.wait_until_ready().await;
+
+    for (index, presence) in CONFIG.presence_rotation.iter().enumerate().cycle() {
+        debug!("Rotating to presence {index}");
+
+        for shard in proxy.iter().flat_map(|state| &state.shards) {
+            let activity = for_shard(&presence.activity, shard.id);
+
+            // Will only error if activities are empty, so we can unwrap
+            let update = UpdatePresence::new(vec![activity], false, None, presence.status).unwrap();
+
+            // Presence updates count against the command ratelimit like any other command
+            if let Ok(command) = to_string(&update) {
+                shard.commands.send(command);
+            }
+        }
+
+        sleep(Duration::from_secs(presence.duration_secs).max(MIN_DURATION)).await;
+    }
+}
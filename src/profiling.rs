@@ -0,0 +1,97 @@
+use http_body_util::Full;
+use hyper::{
+    body::{Bytes, Incoming},
+    header::CONTENT_TYPE,
+    Request, Response, StatusCode,
+};
+use pprof::{protos::Message, ProfilerGuardBuilder};
+use tracing::{error, info};
+
+use std::time::Duration;
+
+use crate::{admin, server::empty_response, upgrade::query_param};
+
+/// Sampling frequency of the profiler in Hz.
+const FREQUENCY: i32 = 99;
+
+const DEFAULT_SECONDS: u64 = 30;
+const MAX_SECONDS: u64 = 300;
+
+/// Handle a request to `/debug/pprof/profile`. The proxy is profiled for the
+/// `seconds` in the query string and the profile returned as a flamegraph, or
+/// in the pprof protobuf format with `format=pprof`.
+pub async fn profile(request: &Request<Incoming>) -> Response<Full<Bytes>> {
+    if let Err(status) = admin::authorize(request) {
+        return empty_response(status);
+    }
+
+    let query = request.uri().query().unwrap_or_default();
+
+    let seconds = match query_param(query, "seconds").map(str::parse) {
+        Some(Ok(seconds)) if seconds > 0 && seconds <= MAX_SECONDS => seconds,
+        Some(_) => return empty_response(StatusCode::BAD_REQUEST),
+        None => DEFAULT_SECONDS,
+    };
+
+    let pprof_format = match query_param(query, "format") {
+        Some("pprof") => true,
+        Some("flamegraph") | None => false,
+        Some(_) => return empty_response(StatusCode::BAD_REQUEST),
+    };
+
+    // Skip frames in the signal handler that interrupts threads for sampling
+    let guard = match ProfilerGuardBuilder::default()
+        .frequency(FREQUENCY)
+        .blocklist(&["libc", "libgcc", "pthread", "vdso"])
+        .build()
+    {
+        Ok(guard) => guard,
+        Err(e) => {
+            // Only one profile can be taken at a time
+            error!("Failed to start profiler: {e}");
+            return empty_response(StatusCode::CONFLICT);
+        }
+    };
+
+    info!("Profiling the proxy for {seconds} seconds");
+    tokio::time::sleep(Duration::from_secs(seconds)).await;
+
+    let report = match guard.report().build() {
+        Ok(report) => report,
+        Err(e) => {
+            error!("Failed to build profile: {e}");
+            return empty_response(StatusCode::INTERNAL_SERVER_ERROR);
+        }
+    };
+
+    let (content_type, encoded) = if pprof_format {
+        let encoded = report
+            .pprof()
+            .map_err(|e| e.to_string())
+            .and_then(|profile| profile.write_to_bytes().map_err(|e| e.to_string()));
+
+        ("application/octet-stream", encoded)
+    } else {
+        let mut body = Vec::new();
+        let encoded = report
+            .flamegraph(&mut body)
+            .map(|()| body)
+            .map_err(|e| e.to_string());
+
+        ("image/svg+xml", encoded)
+    };
+
+    let body = match encoded {
+        Ok(body) => body,
+        Err(e) => {
+            error!("Failed to encode profile: {e}");
+            return empty_response(StatusCode::INTERNAL_SERVER_ERROR);
+        }
+    };
+
+    Response::builder()
+        .status(StatusCode::OK)
+        .header(CONTENT_TYPE, content_type)
+        .body(Full::from(body))
+        .unwrap()
+}
@@ -0,0 +1,142 @@
+use std::{
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+    time::{Duration, Instant},
+};
+use tokio::{
+    sync::mpsc::{unbounded_channel, UnboundedReceiver, UnboundedSender},
+    time::sleep,
+};
+
+use crate::state::ShardSender;
+
+/// Token bucket allowing a number of actions per interval, refilling
+/// continuously.
+pub struct TokenBucket {
+    capacity: f64,
+    tokens: f64,
+    /// Tokens added per second.
+    refill_rate: f64,
+    refilled_at: Instant,
+}
+
+impl TokenBucket {
+    /// Create a full bucket that allows `capacity` actions per `interval`.
+    pub fn new(capacity: u32, interval: Duration) -> Self {
+        let capacity = f64::from(capacity);
+
+        Self {
+            capacity,
+            tokens: capacity,
+            refill_rate: capacity / interval.as_secs_f64(),
+            refilled_at: Instant::now(),
+        }
+    }
+
+    fn refill(&mut self) {
+        let now = Instant::now();
+        let elapsed = now.duration_since(self.refilled_at).as_secs_f64();
+        self.tokens = elapsed
+            .mul_add(self.refill_rate, self.tokens)
+            .min(self.capacity);
+        self.refilled_at = now;
+    }
+
+    /// Take a token from the bucket, returning whether one was available.
+    pub fn try_acquire(&mut self) -> bool {
+        self.refill();
+
+        if self.tokens >= 1.0 {
+            self.tokens -= 1.0;
+            true
+        } else {
+            false
+        }
+    }
+
+    /// Take a token from the bucket, waiting until one is available.
+    pub async fn acquire(&mut self) {
+        while !self.try_acquire() {
+            let missing = 1.0 - self.tokens;
+            sleep(Duration::from_secs_f64(missing / self.refill_rate)).await;
+        }
+    }
+}
+
+/// Commands that a shard can send per minute. Discord allows 120, which also
+/// covers the heartbeats and the commands that twilight sends by itself.
+const COMMANDS_PER_MINUTE: u32 = 115;
+
+/// Queue for the commands that a shard sends to Discord on behalf of clients
+/// and the proxy itself. Commands are sent in the order they were queued, at
+/// no more than Discord's ratelimit.
+pub struct CommandQueue {
+    tx: UnboundedSender<String>,
+    depth: Arc<AtomicUsize>,
+    bot: String,
+    shard_id: u32,
+}
+
+impl CommandQueue {
+    /// Start sending queued commands to a shard.
+    pub fn new(sender: Arc<ShardSender>, bot: String, shard_id: u32) -> Self {
+        let (tx, rx) = unbounded_channel();
+        let depth = Arc::new(AtomicUsize::new(0));
+
+        tokio::spawn(send_commands(
+            sender,
+            rx,
+            depth.clone(),
+            bot.clone(),
+            shard_id,
+        ));
+
+        Self {
+            tx,
+            depth,
+            bot,
+            shard_id,
+        }
+    }
+
+    /// Queue a command to be sent to Discord.
+    pub fn send(&self, command: String) {
+        // Count the command before queueing it, otherwise the sending task
+        // could take it off the queue before it was counted
+        let depth = self.depth.fetch_add(1, Ordering::Relaxed) + 1;
+
+        if self.tx.send(command).is_ok() {
+            update_depth_gauge(&self.bot, self.shard_id, depth);
+        } else {
+            self.depth.fetch_sub(1, Ordering::Relaxed);
+        }
+    }
+}
+
+async fn send_commands(
+    sender: Arc<ShardSender>,
+    mut rx: UnboundedReceiver<String>,
+    depth: Arc<AtomicUsize>,
+    bot: String,
+    shard_id: u32,
+) {
+    let mut bucket = TokenBucket::new(COMMANDS_PER_MINUTE, Duration::from_mins(1));
+
+    while let Some(command) = rx.recv().await {
+        bucket.acquire().await;
+
+        let depth = depth.fetch_sub(1, Ordering::Relaxed) - 1;
+        update_depth_gauge(&bot, shard_id, depth);
+
+        if sender.send(command).is_err() {
+            return;
+        }
+    }
+}
+
+fn update_depth_gauge(bot: &str, shard_id: u32, depth: usize) {
+    metrics::gauge!("gateway_shard_command_queue_depth", "bot" => bot.to_owned(), "shard" => shard_id.to_string())
+        .set(depth as f64);
+}
@@ -1,14 +1,21 @@
 use bytes::Bytes;
-use flate2::{Compress, Compression, FlushCompress, Status};
+use flate2::{write::GzEncoder, Compression as Level};
 use futures_util::{Sink, SinkExt, StreamExt};
 use http_body_util::Full;
-use hyper::{body::Incoming, service::service_fn, Method, Request, Response, StatusCode};
+use hyper::{
+    body::Incoming,
+    header::{ACCEPT_ENCODING, CONTENT_ENCODING, CONTENT_TYPE, UPGRADE, VARY},
+    service::service_fn,
+    Method, Request, Response, StatusCode,
+};
 use hyper_util::{
     rt::{TokioExecutor, TokioIo},
     server::conn::auto,
 };
 use itoa::Buffer;
 use metrics_exporter_prometheus::PrometheusHandle;
+use rand::{thread_rng, Rng};
+use serde::Serialize;
 #[cfg(not(feature = "simd-json"))]
 use serde_json::{to_string, Value as OwnedValue};
 #[cfg(feature = "simd-json")]
@@ -21,93 +28,176 @@ use tokio::{
         mpsc::{unbounded_channel, UnboundedReceiver, UnboundedSender},
         oneshot,
     },
+    time::{interval, timeout, timeout_at, Instant, MissedTickBehavior},
 };
-use tokio_websockets::{Error, Limits, Message, ServerBuilder};
+use tokio_websockets::{CloseCode, Error, Limits, Message, ServerBuilder};
 use tracing::{debug, error, info, trace, warn};
+use twilight_model::{
+    gateway::{
+        payload::outgoing::request_guild_members::RequestGuildMembersInfo,
+        CloseCode as GatewayCloseCode, Intents, OpCode,
+    },
+    id::{marker::GuildMarker, Id},
+};
 
 use std::{
+    borrow::Cow,
+    collections::VecDeque,
     convert::Infallible,
     future::ready,
+    io::Write,
     net::{IpAddr, SocketAddr},
+    ops::Range,
     str::FromStr,
-    sync::Arc,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+    time::Duration,
 };
 
+#[cfg(feature = "pprof")]
+use crate::profiling;
 use crate::{
-    config::CONFIG,
+    admin,
+    cache::Payload,
+    compression::{Compression, Compressor},
+    config::{CacheProfile, SlowClientPolicy, VoiceStatePolicy, CONFIG},
     deserializer::{GatewayEvent, SequenceInfo},
-    model::{Identify, Resume},
-    state::{Session, Shard, State},
+    dispatch::{self, BroadcastEvent, BroadcastMessage},
+    encoding::Encoding,
+    intents,
+    model::{Identify, RequestGuildMembers, Resume, UpdateVoiceState},
+    ratelimit::TokenBucket,
+    state::{ClientActivity, Proxy, Session, Shard, State, TokenOwner},
     upgrade,
 };
 
-const HELLO: &str = r#"{"t":null,"s":null,"op":10,"d":{"heartbeat_interval":41250}}"#;
 const HEARTBEAT_ACK: &str = r#"{"t":null,"s":null,"op":11,"d":null}"#;
 const INVALID_SESSION: &str = r#"{"t":null,"s":null,"op":9,"d":false}"#;
 const RESUMED: &str = r#"{"t":"RESUMED","s":null,"op":0,"d":{}}"#;
 
-const TRAILER: [u8; 4] = [0x00, 0x00, 0xff, 0xff];
+/// Proxy-specific op that clients send to get the `GUILD_CREATE`s of their
+/// shard again, without a new READY.
+const GUILD_REPLAY_OP: u8 = 12;
 
-fn compress_full(compressor: &mut Compress, output: &mut Vec<u8>, input: &[u8]) {
-    let before_in = compressor.total_in() as usize;
-    while (compressor.total_in() as usize) - before_in < input.len() {
-        let offset = (compressor.total_in() as usize) - before_in;
-        match compressor
-            .compress_vec(&input[offset..], output, FlushCompress::None)
-            .unwrap()
-        {
-            Status::Ok => continue,
-            Status::BufError => output.reserve(4096),
-            Status::StreamEnd => break,
-        }
+/// How often a batched guild replay checks whether the client has received the
+/// previous batch.
+const GUILD_REPLAY_DRAIN_INTERVAL: Duration = Duration::from_millis(10);
+
+/// Amount of clients connected to the proxy.
+static CLIENTS: AtomicUsize = AtomicUsize::new(0);
+
+/// A client counted towards `max_clients` for as long as it is alive.
+pub struct ClientSlot {
+    encoding: Encoding,
+    compression: Option<Compression>,
+}
+
+impl ClientSlot {
+    /// Take up a slot for a new client, if the limit has not been reached.
+    pub fn acquire(encoding: Encoding, compression: Option<Compression>) -> Option<Self> {
+        let max_clients = CONFIG.max_clients.unwrap_or(usize::MAX);
+
+        CLIENTS
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |clients| {
+                (clients < max_clients).then_some(clients + 1)
+            })
+            .ok()?;
+
+        metrics::gauge!("gateway_clients", "encoding" => encoding.name(), "compression" => compression_label(compression))
+            .increment(1.0);
+
+        Some(Self {
+            encoding,
+            compression,
+        })
     }
+}
 
-    while !output.ends_with(&TRAILER) {
-        output.reserve(5);
-        match compressor
-            .compress_vec(&[], output, FlushCompress::Sync)
-            .unwrap()
-        {
-            Status::Ok | Status::BufError => continue,
-            Status::StreamEnd => break,
-        }
+impl Drop for ClientSlot {
+    fn drop(&mut self) {
+        CLIENTS.fetch_sub(1, Ordering::Relaxed);
+
+        metrics::gauge!("gateway_clients", "encoding" => self.encoding.name(), "compression" => compression_label(self.compression))
+            .decrement(1.0);
     }
 }
 
+/// Label value for the transport compression of a client in metrics.
+pub fn compression_label(compression: Option<Compression>) -> &'static str {
+    compression.map_or("none", Compression::name)
+}
+
+/// Time that queued messages get to be flushed after a client disconnected.
+const FLUSH_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// Create a HELLO payload. Like Discord, the heartbeat interval is jittered
+/// so that clients don't all send their heartbeats at once.
+#[allow(clippy::cast_sign_loss)]
+fn hello() -> String {
+    let jitter = CONFIG.heartbeat_jitter.clamp(0.0, 1.0);
+    let factor = if jitter > 0.0 {
+        thread_rng().gen_range(1.0 - jitter..=1.0 + jitter)
+    } else {
+        1.0
+    };
+    let heartbeat_interval = (CONFIG.heartbeat_interval as f64 * factor) as u64;
+
+    format!(r#"{{"t":null,"s":null,"op":10,"d":{{"heartbeat_interval":{heartbeat_interval}}}}}"#)
+}
+
 async fn sink_from_queue<S>(
     addr: SocketAddr,
-    mut use_zlib: bool,
+    compression: Option<Compression>,
+    context_takeover: bool,
     compress_rx: oneshot::Receiver<Option<bool>>,
+    activity: Arc<ClientActivity>,
     mut message_stream: UnboundedReceiver<Message>,
     mut sink: S,
 ) -> Result<(), Error>
 where
     S: Sink<Message, Error = Error> + Unpin + Send,
 {
-    // Initialize a zlib encoder with similar settings to Discord's
-    let mut compress = Compress::new(Compression::fast(), true);
-    let mut compression_buffer = Vec::with_capacity(32 * 1024);
+    // The compression stream lives as long as the connection. Resuming always
+    // happens on a new connection, and a connection only ever serves a single
+    // session, so a client's decompressor never has to be reset mid-stream.
+    let mut compressor =
+        compression.map(|compression| Compressor::new(compression, context_takeover));
+    let mut compression_buffer = Vec::with_capacity(CONFIG.compression_buffer_size);
 
     // At first, we will have to send a HELLO
-    if use_zlib {
-        compress_full(&mut compress, &mut compression_buffer, HELLO.as_bytes());
+    let hello = hello();
+
+    if let Some(compressor) = &mut compressor {
+        compressor.compress(&mut compression_buffer, hello.as_bytes());
 
         sink.send(Message::binary(Bytes::from(compression_buffer.clone())))
             .await?;
     } else {
-        sink.send(Message::text(HELLO.to_string())).await?;
+        sink.send(Message::text(hello)).await?;
     }
 
-    if compress_rx.await == Ok(Some(true)) {
-        use_zlib = true;
+    // Compression requested in IDENTIFY is always zlib
+    if compressor.is_none() && compress_rx.await == Ok(Some(true)) {
+        compressor = Some(Compressor::new(Compression::Zlib, context_takeover));
     }
 
+    // Every message is compressed with a sync flush, so nothing is left in the
+    // compressor when the connection closes and the client's decompressor never
+    // sees a truncated block. Finishing the stream is not needed, Discord doesn't
+    // either.
     while let Some(msg) = message_stream.recv().await {
         trace!("[{addr}] Sending {msg:?}");
+        activity
+            .queued
+            .store(message_stream.len(), Ordering::Relaxed);
+        activity.received.fetch_add(1, Ordering::Relaxed);
 
-        if use_zlib {
+        // Close frames are control frames and must never be compressed
+        if let Some(compressor) = compressor.as_mut().filter(|_| !msg.is_close()) {
             compression_buffer.clear();
-            compress_full(&mut compress, &mut compression_buffer, &msg.into_payload());
+            compressor.compress(&mut compression_buffer, &msg.into_payload());
 
             sink.send(Message::binary(Bytes::from(compression_buffer.clone())))
                 .await?;
@@ -119,64 +209,755 @@ where
     Ok(())
 }
 
+#[allow(clippy::redundant_pub_crate, clippy::too_many_lines)]
 async fn forward_shard(
     session_id: String,
     shard_status: Arc<Shard>,
     stream_writer: UnboundedSender<Message>,
-    send_guilds: bool,
-    mut seq: usize,
+    mut cached_requests: UnboundedReceiver<CachedRequest>,
+    resume_seq: Option<usize>,
+    session: Session,
+    activity: Arc<ClientActivity>,
 ) {
     let shard_id = shard_status.id;
+    let Session {
+        seq: session_seq,
+        intents,
+        replay_guilds,
+        cache_profile,
+        history,
+        guild_ids,
+        ..
+    } = session;
+
+    // The sequence is stored in the session so that resuming continues it, this
+    // task keeps a copy and writes it back whenever it sends something
+    let mut seq = session_seq.load(Ordering::Relaxed);
 
     debug!("[Shard {shard_id}] Starting to send events to client",);
 
-    // Wait until we have a valid READY payload for this shard
-    let ready_payload = shard_status.ready.wait_until_ready().await;
-
-    if send_guilds {
-        // Get a fake ready payload to send to the client
-        let mut ready_payload = shard_status
-            .guilds
-            .get_ready_payload(ready_payload, &mut seq);
-
-        // Overwrite the session ID in the READY
-        ready_payload
-            .d
-            .insert(String::from("session_id"), OwnedValue::String(session_id));
-
-        if let Ok(serialized) = to_string(&ready_payload) {
-            debug!("[Shard {shard_id}] Sending newly created READY");
-            let _res = stream_writer.send(Message::text(serialized));
-        };
+    // Subscribe to events for this shard before sending READY, so that events
+    // broadcast while the guilds are replayed aren't missed. The guard is
+    // declared first so that it is dropped after the receiver and sees the
+    // updated subscriber count.
+    let _subscriber_guard = SubscriberGauge(&shard_status);
+    let mut event_receiver = shard_status.events.subscribe();
+    let start_index = shard_status.history.next_index();
+    update_subscriber_gauge(&shard_status);
+
+    let ready = if resume_seq.is_none() {
+        send_ready(
+            &shard_status,
+            &session_id,
+            &stream_writer,
+            &mut seq,
+            replay_guilds.then_some(cache_profile),
+            &activity,
+        )
+        .await
+    } else {
+        // Wait until we have a valid READY payload for this shard
+        shard_status.wait_until_ready().await.map(|_| None)
+    };
 
-        // Send GUILD_CREATE/GUILD_DELETEs based on guild availability
-        for payload in shard_status.guilds.get_guild_payloads(&mut seq) {
-            trace!("[Shard {shard_id}] Sending newly created GUILD_CREATE/GUILD_DELETE payload");
-            let _res = stream_writer.send(Message::text(payload));
+    // Remaining batches of a guild replay, sent in between events
+    let mut guild_replay = match ready {
+        Ok(guild_replay) => guild_replay,
+        Err(code) => {
+            debug!("[Shard {shard_id}] Disconnecting client because the shard failed");
+            let _res = stream_writer.send(close_frame(code, "Shard failed"));
+            return;
         }
-    } else {
-        let _res = stream_writer.send(Message::text(RESUMED.to_string()));
-    }
+    };
+    let mut replay_check = interval(GUILD_REPLAY_DRAIN_INTERVAL);
+    replay_check.set_missed_tick_behavior(MissedTickBehavior::Delay);
+
+    session_seq.store(seq, Ordering::Relaxed);
 
     // For formatting the sequence number as a string, reuse a buffer
     let mut buffer = Buffer::new();
 
-    // Subscribe to events for this shard
-    let mut event_receiver = shard_status.events.subscribe();
+    // Events from the history are read after subscribing, so that none are
+    // missed in between. Events received from both are only sent once.
+    let mut replay = VecDeque::new();
+
+    if let Some(history) = &history {
+        let mut history = history.lock().unwrap();
+
+        if let Some(resume_seq) = resume_seq {
+            let Some(events) = history
+                .resume(resume_seq, seq)
+                .and_then(|index| shard_status.history.since(index))
+            else {
+                debug!("[Shard {shard_id}] Events after sequence {resume_seq} are not in the history, disconnecting client");
+                let _res = stream_writer.send(close_frame(
+                    GatewayCloseCode::SessionTimedOut,
+                    "Session timed out",
+                ));
+                return;
+            };
+
+            debug!(
+                "[Shard {shard_id}] Replaying {} events to client",
+                events.len()
+            );
+            seq = resume_seq;
+            session_seq.store(seq, Ordering::Relaxed);
+            replay.extend(events);
+        } else {
+            history.start_at(start_index);
+        }
+    }
+
+    if resume_seq.is_some() {
+        activity.send(&stream_writer, Message::text(RESUMED.to_string()));
+    }
+
+    // Recorded for every event, so the labels are only built once
+    let dispatch_latency = metrics::histogram!("gateway_shard_dispatch_latency_seconds", "bot" => shard_status.bot.clone(), "shard" => shard_id.to_string());
 
     loop {
-        let res = event_receiver.recv().await;
+        // Events replayed from the history were received long before
+        let (res, replayed) = if let Some(event) = replay.pop_front() {
+            (Ok(BroadcastMessage::Event(event)), true)
+        } else {
+            let res = tokio::select! {
+                res = event_receiver.recv() => res,
+                _ = replay_check.tick(), if guild_replay.is_some() => {
+                    // Stop building payloads for a client that is gone
+                    if stream_writer.is_closed() {
+                        return;
+                    }
+
+                    guild_replay = guild_replay.and_then(|guild_replay| {
+                        guild_replay.next_batch(&shard_status, &stream_writer, &mut seq, &activity)
+                    });
+                    session_seq.store(seq, Ordering::Relaxed);
+
+                    continue;
+                }
+                Some(request) = cached_requests.recv() => {
+                    // Requests answered from the cache are part of this session's
+                    // sequence, so they are sent from here
+                    match request {
+                        CachedRequest::Members(request) => {
+                            let guild_id = request.guild_id;
+                            trace!("[Shard {shard_id}] Sending cached members of guild {guild_id}");
+
+                            let chunks = shard_status.guilds.get_member_chunks(&request, &mut seq);
+
+                            for payload in chunks {
+                                activity.send(&stream_writer, Message::text(payload));
+                            }
+                        }
+                        CachedRequest::Guilds => {
+                            debug!("[Shard {shard_id}] Replaying guilds to client");
+                            guild_replay = send_guild_payloads(
+                                &shard_status,
+                                &stream_writer,
+                                &mut seq,
+                                cache_profile,
+                                &activity,
+                            );
+                        }
+                    }
+
+                    session_seq.store(seq, Ordering::Relaxed);
+
+                    continue;
+                }
+            };
+
+            (res, false)
+        };
+
+        match res {
+            Ok(BroadcastMessage::Event(BroadcastEvent {
+                mut payload,
+                sequence,
+                session_id: target,
+                intents: required_intents,
+                guild_id,
+                received_at,
+                index,
+            })) => {
+                let mut history = history.as_deref().map(|history| history.lock().unwrap());
+
+                if history
+                    .as_ref()
+                    .is_some_and(|history| history.has_seen(index))
+                {
+                    continue;
+                }
+
+                // Events are received oldest first, so a client that is too far
+                // behind skips them until it has caught up
+                if CONFIG.slow_client_policy == SlowClientPolicy::DropOldest
+                    && CONFIG
+                        .slow_client_threshold
+                        .is_some_and(|threshold| event_receiver.len() > threshold)
+                {
+                    if let Some(history) = &mut history {
+                        history.skipped(index);
+                    }
+
+                    count_slow_client_drops(&shard_status, 1);
+                    continue;
+                }
+
+                // Skip events meant for another client, they aren't part of this
+                // session's sequence. Discord also wouldn't send events that
+                // the client's intents don't cover. Events that aren't for a
+                // guild pass the client's guild filter.
+                if target.is_some_and(|target| target != session_id)
+                    || intents
+                        .is_some_and(|intents| !intents::is_sent_for(required_intents, intents))
+                    || guild_id.is_some_and(|guild_id| {
+                        guild_ids
+                            .as_ref()
+                            .is_some_and(|guild_ids| !guild_ids.contains(&guild_id))
+                    })
+                {
+                    if let Some(history) = &mut history {
+                        history.skipped(index);
+                    }
+
+                    continue;
+                }
+
+                // Overwrite the sequence number
+                if let Some(sequence) = sequence {
+                    seq += 1;
+                    session_seq.store(seq, Ordering::Relaxed);
+                    rewrite_sequence(shard_id, &mut payload, sequence, buffer.format(seq));
+
+                    if let Some(history) = &mut history {
+                        history.sent(seq, index, CONFIG.resume_buffer_size);
+                    }
+                } else if let Some(history) = &mut history {
+                    history.skipped(index);
+                }
+
+                drop(history);
+
+                activity.send(&stream_writer, Message::text(payload));
+
+                if !replayed {
+                    dispatch_latency.record(received_at.elapsed().as_secs_f64());
+                }
+
+                if let Some(threshold) = CONFIG.slow_client_threshold {
+                    let depth = event_receiver.len();
+
+                    if depth > threshold
+                        && CONFIG.slow_client_policy == SlowClientPolicy::Disconnect
+                    {
+                        warn!("[Shard {shard_id}] Client is {depth} events behind, disconnecting");
+                        disconnect_slow_client(&shard_status, &stream_writer);
+                        return;
+                    }
+                }
+            }
+            Ok(BroadcastMessage::Resync) => {
+                debug!("[Shard {shard_id}] Resyncing client");
+
+                // A shard that failed in the meantime disconnects the client with
+                // its own broadcast
+                if let Ok(resync_replay) = send_ready(
+                    &shard_status,
+                    &session_id,
+                    &stream_writer,
+                    &mut seq,
+                    replay_guilds.then_some(cache_profile),
+                    &activity,
+                )
+                .await
+                {
+                    guild_replay = resync_replay;
+                }
+
+                session_seq.store(seq, Ordering::Relaxed);
+            }
+            Ok(BroadcastMessage::Shutdown) => {
+                debug!("[Shard {shard_id}] Disconnecting client for shutdown");
+                let _res = stream_writer.send(close_message(
+                    Some(CloseCode::GOING_AWAY),
+                    "Proxy is shutting down",
+                ));
+                return;
+            }
+            Ok(BroadcastMessage::Failed(code)) => {
+                debug!("[Shard {shard_id}] Disconnecting client because the shard failed");
+                let _res = stream_writer.send(close_frame(code, "Shard failed"));
+                return;
+            }
+            Ok(BroadcastMessage::TokenChanged) => {
+                debug!("[Shard {shard_id}] Disconnecting client for token change");
+                let _res = stream_writer.send(close_frame(
+                    GatewayCloseCode::AuthenticationFailed,
+                    "Token changed",
+                ));
+                return;
+            }
+            Err(RecvError::Lagged(amt)) => {
+                warn!("[Shard {shard_id}] Client is {amt} events behind!");
+                count_slow_client_drops(&shard_status, amt);
+
+                if CONFIG.slow_client_policy == SlowClientPolicy::Disconnect {
+                    disconnect_slow_client(&shard_status, &stream_writer);
+                    return;
+                }
+            }
+            Err(RecvError::Closed) => return,
+        }
+    }
+}
+
+/// Check that a range of a payload contains exactly the sequence number.
+fn is_sequence_at(payload: &str, range: &Range<usize>, sequence: u64) -> bool {
+    payload
+        .get(range.clone())
+        .and_then(|digits| digits.trim().parse().ok())
+        == Some(sequence)
+}
+
+/// Replace the sequence number of a payload. The range was found in the
+/// payload when it was received from Discord, but it is looked up again if it
+/// doesn't delimit the original sequence number in this payload. If that fails
+/// too, the payload is left untouched rather than corrupting it.
+fn rewrite_sequence(shard_id: u32, payload: &mut String, original: SequenceInfo, sequence: &str) {
+    let SequenceInfo(original_sequence, range) = original;
+
+    let range = if is_sequence_at(payload, &range, original_sequence) {
+        range
+    } else if let Some((_, Some(SequenceInfo(_, range)), _)) =
+        GatewayEvent::from_json(payload).map(GatewayEvent::into_parts)
+    {
+        if !is_sequence_at(payload, &range, original_sequence) {
+            warn!("[Shard {shard_id}] Sequence {original_sequence} not found in payload, not rewriting it");
+            return;
+        }
+
+        range
+    } else {
+        warn!("[Shard {shard_id}] Payload has no sequence, not rewriting it");
+        return;
+    };
+
+    payload.replace_range(range, sequence);
+}
+
+/// Send a READY built from the cache to a client, followed by the guilds of the
+/// shard as selected by the cache profile. The sequence continues from the
+/// client's current one. Returns the rest of a batched guild replay, or the
+/// close code of the shard if it failed before it got a READY.
+async fn send_ready(
+    shard_status: &Shard,
+    session_id: &str,
+    stream_writer: &UnboundedSender<Message>,
+    seq: &mut usize,
+    cache_profile: Option<&'static CacheProfile>,
+    activity: &ClientActivity,
+) -> Result<Option<GuildReplay>, GatewayCloseCode> {
+    let shard_id = shard_status.id;
+
+    // Wait until we have a valid READY payload for this shard
+    let ready_payload = shard_status.wait_until_ready().await?;
+
+    // Get a fake ready payload to send to the client, unless it's the first
+    // client and gets the one from Discord
+    let mut ready_payload = if let Some(first) = shard_status.ready.take_first() {
+        debug!("[Shard {shard_id}] Sending the READY from Discord to the first client");
+        *seq += 1;
+
+        Payload {
+            d: first,
+            op: OpCode::Dispatch,
+            t: "READY",
+            s: *seq,
+        }
+    } else {
+        shard_status.guilds.get_ready_payload(ready_payload, seq)
+    };
+
+    // Overwrite the session ID in the READY
+    ready_payload.d.insert(
+        String::from("session_id"),
+        OwnedValue::String(session_id.to_string()),
+    );
+
+    if let Ok(serialized) = to_string(&ready_payload) {
+        debug!("[Shard {shard_id}] Sending newly created READY");
+        activity.send(stream_writer, Message::text(serialized));
+    };
 
-        if let Ok((mut payload, sequence)) = res {
-            // Overwrite the sequence number
-            if let Some(SequenceInfo(_, sequence_range)) = sequence {
-                seq += 1;
-                payload.replace_range(sequence_range, buffer.format(seq));
+    Ok(cache_profile.and_then(|cache_profile| {
+        send_guild_payloads(shard_status, stream_writer, seq, cache_profile, activity)
+    }))
+}
+
+/// Send a `GUILD_CREATE` built from the cache to a client for every guild of
+/// the shard, or a `GUILD_DELETE` for unavailable ones. With batches, only the
+/// first one is sent and the rest of the replay is returned.
+fn send_guild_payloads(
+    shard_status: &Shard,
+    stream_writer: &UnboundedSender<Message>,
+    seq: &mut usize,
+    cache_profile: &'static CacheProfile,
+    activity: &ClientActivity,
+) -> Option<GuildReplay> {
+    let Some(batch_size) = CONFIG.guild_replay_batch_size.filter(|&size| size > 0) else {
+        // Send GUILD_CREATE/GUILD_DELETEs based on guild availability
+        for (guild_id, payload) in shard_status.guilds.get_guild_payloads(seq, cache_profile) {
+            send_guild_payload(shard_status, stream_writer, activity, guild_id, payload);
+        }
+
+        return None;
+    };
+
+    // Payloads are only built once their batch is sent, so that a client that
+    // is slow to receive them doesn't hold all of them in its queue at once
+    GuildReplay {
+        guild_ids: shard_status.guilds.guild_ids().into(),
+        cache_profile,
+        batch_size,
+        last_sent: 0,
+        send_at: None,
+    }
+    .send_batch(shard_status, stream_writer, seq, activity)
+}
+
+/// Guilds of a batched replay that are still to be sent to a client. Events
+/// are sent in between batches, so that they don't pile up in the shard's
+/// broadcast queue while the client receives the guilds.
+struct GuildReplay {
+    guild_ids: VecDeque<Id<GuildMarker>>,
+    cache_profile: &'static CacheProfile,
+    batch_size: usize,
+    /// Value of `ClientActivity::sent` after the last message of the previous
+    /// batch was queued.
+    last_sent: usize,
+    /// When the next batch is sent, once the client received the previous one.
+    send_at: Option<Instant>,
+}
+
+impl GuildReplay {
+    /// Send the next batch if the client received the previous one and the
+    /// pause after it is over. Returns the replay unless it is done.
+    fn next_batch(
+        mut self,
+        shard_status: &Shard,
+        stream_writer: &UnboundedSender<Message>,
+        seq: &mut usize,
+        activity: &ClientActivity,
+    ) -> Option<Self> {
+        if activity.received.load(Ordering::Relaxed) < self.last_sent {
+            return Some(self);
+        }
+
+        let now = Instant::now();
+        let send_at = *self
+            .send_at
+            .get_or_insert_with(|| now + Duration::from_millis(CONFIG.guild_replay_batch_pause_ms));
+
+        if now < send_at {
+            return Some(self);
+        }
+
+        self.send_batch(shard_status, stream_writer, seq, activity)
+    }
+
+    fn send_batch(
+        mut self,
+        shard_status: &Shard,
+        stream_writer: &UnboundedSender<Message>,
+        seq: &mut usize,
+        activity: &ClientActivity,
+    ) -> Option<Self> {
+        let batch_size = self.batch_size.min(self.guild_ids.len());
+
+        for guild_id in self.guild_ids.drain(..batch_size) {
+            if let Some(payload) =
+                shard_status
+                    .guilds
+                    .get_guild_payload(guild_id, seq, self.cache_profile)
+            {
+                send_guild_payload(shard_status, stream_writer, activity, guild_id, payload);
             }
+        }
+
+        // Read after queueing the batch, so that it covers every message that
+        // is ahead of the batch's last one in the queue
+        self.last_sent = activity.sent.load(Ordering::Relaxed);
+        self.send_at = None;
+
+        (!self.guild_ids.is_empty()).then_some(self)
+    }
+}
+
+/// Send a single `GUILD_CREATE` or `GUILD_DELETE` payload to a client.
+fn send_guild_payload(
+    shard_status: &Shard,
+    stream_writer: &UnboundedSender<Message>,
+    activity: &ClientActivity,
+    guild_id: Id<GuildMarker>,
+    payload: String,
+) {
+    let shard_id = shard_status.id;
+
+    trace!("[Shard {shard_id}] Sending newly created GUILD_CREATE/GUILD_DELETE payload");
+
+    if CONFIG
+        .guild_payload_warn_size
+        .is_some_and(|size| payload.len() > size)
+    {
+        warn!(
+            "[Shard {shard_id}] GUILD_CREATE for guild {guild_id} is {} bytes, clients may fail to receive it",
+            payload.len()
+        );
+        metrics::counter!("gateway_client_oversized_guild_payloads", "bot" => shard_status.bot.clone(), "shard" => shard_id.to_string())
+            .increment(1);
+    }
 
-            let _res = stream_writer.send(Message::text(payload));
-        } else if let Err(RecvError::Lagged(amt)) = res {
-            warn!("[Shard {shard_id}] Client is {amt} events behind!");
+    activity.send(stream_writer, Message::text(payload));
+}
+
+/// Describe why a payload couldn't be deserialized: `syntax` for invalid JSON,
+/// `eof` for truncated JSON and `data` for JSON that doesn't match the payload.
+#[cfg(feature = "simd-json")]
+fn json_error_category(error: &simd_json::Error) -> &'static str {
+    use simd_json::ErrorType;
+
+    match error.error() {
+        ErrorType::Eof => "eof",
+        ErrorType::Serde(_)
+        | ErrorType::Unexpected(..)
+        | ErrorType::ExpectedArray
+        | ErrorType::ExpectedBoolean
+        | ErrorType::ExpectedEnum
+        | ErrorType::ExpectedFloat
+        | ErrorType::ExpectedInteger
+        | ErrorType::ExpectedMap
+        | ErrorType::ExpectedNull
+        | ErrorType::ExpectedNumber
+        | ErrorType::ExpectedSigned
+        | ErrorType::ExpectedString
+        | ErrorType::ExpectedUnsigned
+        | ErrorType::Overflow => "data",
+        _ => "syntax",
+    }
+}
+
+/// Describe why a payload couldn't be deserialized: `syntax` for invalid JSON,
+/// `eof` for truncated JSON and `data` for JSON that doesn't match the payload.
+#[cfg(not(feature = "simd-json"))]
+fn json_error_category(error: &serde_json::Error) -> &'static str {
+    match error.classify() {
+        serde_json::error::Category::Eof => "eof",
+        serde_json::error::Category::Data => "data",
+        serde_json::error::Category::Syntax | serde_json::error::Category::Io => "syntax",
+    }
+}
+
+/// Request of a client that its forwarding task answers from the cache.
+enum CachedRequest {
+    /// `REQUEST_GUILD_MEMBERS` for a guild whose members are all cached.
+    Members(RequestGuildMembersInfo),
+    /// Replay of the shard's guilds, see [`GUILD_REPLAY_OP`].
+    Guilds,
+}
+
+/// Updates the subscriber gauge of a shard when a client stops receiving events.
+struct SubscriberGauge<'a>(&'a Shard);
+
+impl Drop for SubscriberGauge<'_> {
+    fn drop(&mut self) {
+        update_subscriber_gauge(self.0);
+    }
+}
+
+fn update_subscriber_gauge(shard: &Shard) {
+    metrics::gauge!("gateway_shard_subscribers", "bot" => shard.bot.clone(), "shard" => shard.id.to_string())
+        .set(shard.events.receiver_count() as f64);
+}
+
+/// Count events that a client missed because it couldn't keep up with its shard.
+fn count_slow_client_drops(shard: &Shard, amount: u64) {
+    metrics::counter!("gateway_shard_slow_client_dropped_events", "bot" => shard.bot.clone(), "shard" => shard.id.to_string())
+        .increment(amount);
+}
+
+/// Close the connection of a client that can't keep up with its shard.
+///
+/// The client missed events, so it is told that its session timed out, which
+/// makes it reconnect with a fresh IDENTIFY instead of resuming.
+fn disconnect_slow_client(shard: &Shard, stream_writer: &UnboundedSender<Message>) {
+    metrics::counter!("gateway_shard_slow_client_disconnects", "bot" => shard.bot.clone(), "shard" => shard.id.to_string())
+        .increment(1);
+
+    let _res = stream_writer.send(close_frame(
+        GatewayCloseCode::SessionTimedOut,
+        "Client fell too far behind",
+    ));
+}
+
+/// Parse a member request and check whether it can be answered from the cache,
+/// which requires all members of the guild, and their presences if requested,
+/// to be cached.
+fn cached_member_request(payload: &str, shard: &Shard) -> Option<RequestGuildMembersInfo> {
+    if !CONFIG.cache.members {
+        return None;
+    }
+
+    // The payload is forwarded to Discord as is otherwise, so parse a copy
+    #[cfg(feature = "simd-json")]
+    let request: RequestGuildMembers =
+        unsafe { simd_json::from_str(&mut payload.to_owned()) }.ok()?;
+    #[cfg(not(feature = "simd-json"))]
+    let request: RequestGuildMembers = serde_json::from_str(payload).ok()?;
+
+    if request.d.presences == Some(true) && !CONFIG.cache.presences {
+        return None;
+    }
+
+    shard
+        .guilds
+        .has_all_members(request.d.guild_id)
+        .then_some(request.d)
+}
+
+/// Track the voice connection that a voice state update is for and check
+/// whether it should be sent to Discord.
+fn claim_voice_state(addr: SocketAddr, payload: &str, shard: &Shard, session_id: &str) -> bool {
+    #[cfg(feature = "simd-json")]
+    let update: Option<UpdateVoiceState> =
+        unsafe { simd_json::from_str(&mut payload.to_owned()) }.ok();
+    #[cfg(not(feature = "simd-json"))]
+    let update: Option<UpdateVoiceState> = serde_json::from_str(payload).ok();
+
+    // Let Discord deal with invalid payloads
+    let Some(update) = update else {
+        return true;
+    };
+
+    let guild_id = update.d.guild_id;
+    let leaving = update.d.channel_id.is_none();
+
+    let Some(owner) = shard.voice_owners.claim(guild_id, session_id, leaving) else {
+        return true;
+    };
+
+    warn!("[{addr}] Voice connection in guild {guild_id} is owned by session {owner}");
+
+    CONFIG.voice_state_policy != VoiceStatePolicy::Reject
+}
+
+/// Replace the values of all `token` fields in a JSON payload so that it can be
+/// logged safely.
+fn redact_tokens(payload: &str) -> Cow<'_, str> {
+    const KEY: &str = r#""token""#;
+
+    if !payload.contains(KEY) {
+        return Cow::Borrowed(payload);
+    }
+
+    let mut redacted = String::with_capacity(payload.len());
+    let mut rest = payload;
+
+    while let Some(index) = rest.find(KEY) {
+        let (before, after) = rest.split_at(index + KEY.len());
+        redacted.push_str(before);
+
+        // Only string values are redacted, "token" may as well be a value itself
+        let Some(value) = after
+            .trim_start()
+            .strip_prefix(':')
+            .and_then(|value| value.trim_start().strip_prefix('"'))
+        else {
+            rest = after;
+            continue;
+        };
+
+        // Find the closing quote, skipping escaped characters
+        let mut escaped = false;
+        let end = value.bytes().position(|byte| {
+            let is_end = byte == b'"' && !escaped;
+            escaped = byte == b'\\' && !escaped;
+            is_end
+        });
+
+        redacted.push_str(r#":"[redacted]""#);
+        rest = end.map_or("", |end| &value[end + 1..]);
+    }
+
+    redacted.push_str(rest);
+
+    Cow::Owned(redacted)
+}
+
+/// Counter for the bytes received from a client, labeled with its shard.
+fn bytes_received_counter(
+    state: &State,
+    shard_label: &str,
+    encoding: Encoding,
+    compression: Option<Compression>,
+) -> metrics::Counter {
+    metrics::counter!("gateway_client_bytes_received", "bot" => state.name.clone(), "shard" => shard_label.to_owned(), "encoding" => encoding.name(), "compression" => compression_label(compression))
+}
+
+/// Create a close frame with a Discord gateway close code.
+fn close_frame(code: GatewayCloseCode, reason: &str) -> Message {
+    close_message(CloseCode::try_from(code as u16).ok(), reason)
+}
+
+/// Close frame for a connection whose session was resumed on another one.
+fn session_taken_over() -> Message {
+    close_frame(
+        GatewayCloseCode::SessionTimedOut,
+        "Session resumed on another connection",
+    )
+}
+
+/// Maximum length of the reason in a close frame, which has to fit into a
+/// control frame with the close code.
+const MAX_CLOSE_REASON_LEN: usize = 123;
+
+#[derive(Serialize)]
+struct CloseReason<'a> {
+    message: &'a str,
+    retry_after_ms: u64,
+}
+
+/// Create a close frame. If a backoff is configured for the code, the reason is
+/// a JSON object with the `message` and the `retry_after_ms` to wait before
+/// reconnecting.
+fn close_message(code: Option<CloseCode>, reason: &str) -> Message {
+    let backoff = code.and_then(|code| CONFIG.close_backoff_ms.get(&u16::from(code)));
+
+    Message::close(code, &close_reason(reason, backoff.copied()))
+}
+
+/// Reason of a close frame with an optional backoff. The bare reason is used if
+/// the JSON object would be too long for a close frame.
+fn close_reason(reason: &str, backoff: Option<u64>) -> Cow<'_, str> {
+    let json = backoff.and_then(|retry_after_ms| {
+        to_string(&CloseReason {
+            message: reason,
+            retry_after_ms,
+        })
+        .ok()
+    });
+
+    match json {
+        Some(json) if json.len() <= MAX_CLOSE_REASON_LEN => Cow::Owned(json),
+        _ => {
+            let mut end = reason.len().min(MAX_CLOSE_REASON_LEN);
+
+            while !reason.is_char_boundary(end) {
+                end -= 1;
+            }
+
+            Cow::Borrowed(&reason[..end])
         }
     }
 }
@@ -186,7 +967,10 @@ pub async fn handle_client<S: 'static + AsyncRead + AsyncWrite + Unpin + Send>(
     addr: SocketAddr,
     stream: S,
     state: State,
-    use_zlib: bool,
+    compression: Option<Compression>,
+    context_takeover: bool,
+    encoding: Encoding,
+    cache_profile: &'static CacheProfile,
 ) -> Result<(), Error> {
     // We use a oneshot channel to tell the forwarding task whether the IDENTIFY
     // contained a compression request
@@ -194,10 +978,26 @@ pub async fn handle_client<S: 'static + AsyncRead + AsyncWrite + Unpin + Send>(
     let mut compress_tx = Some(compress_tx);
 
     // We need to know which shard this client is connected to in order to send messages to it
-    let mut shard_sender = None;
+    let mut shard_sender: Option<Arc<Shard>> = None;
+    let mut client_session_id = None;
+    let mut invalid_identifies = 0;
+
+    let (client_id, activity) = state.clients.register(addr, compression, encoding);
+
+    // Commands sent to Discord count against the limit of the shared shard
+    let mut ratelimit = CONFIG.client_ratelimit.as_ref().map(|ratelimit| {
+        TokenBucket::new(
+            ratelimit.commands,
+            Duration::from_millis(ratelimit.interval_ms),
+        )
+    });
+
+    // Member requests that can be answered from the cache are handed to the forwarding task
+    let (cached_request_tx, cached_request_rx) = unbounded_channel();
+    let mut cached_request_rx = Some(cached_request_rx);
 
     let ws_conn = ServerBuilder::new()
-        .limits(Limits::unlimited())
+        .limits(Limits::default().max_payload_len(Some(CONFIG.max_message_size)))
         .serve(stream);
 
     let (sink, mut stream) = ws_conn.split();
@@ -207,23 +1007,72 @@ pub async fn handle_client<S: 'static + AsyncRead + AsyncWrite + Unpin + Send>(
 
     let sink_task = tokio::spawn(sink_from_queue(
         addr,
-        use_zlib,
+        compression,
+        context_takeover,
         compress_rx,
+        activity.clone(),
         stream_receiver,
         sink,
     ));
 
     let mut shard_forward_task = None;
 
-    while let Some(Ok(msg)) = stream.next().await {
+    // The shard is only known once the client identified or resumed, so metrics
+    // are labeled with it from then on
+    let mut shard_label = String::from("none");
+    let mut bytes_received = bytes_received_counter(&state, &shard_label, encoding, compression);
+
+    // Clients that don't IDENTIFY or RESUME in time are disconnected
+    let identify_deadline = Instant::now() + Duration::from_millis(CONFIG.identify_timeout_ms);
+
+    loop {
+        let next = if shard_forward_task.is_none() {
+            if let Ok(next) = timeout_at(identify_deadline, stream.next()).await {
+                next
+            } else {
+                warn!("[{addr}] Client did not identify in time, disconnecting");
+                let _res = stream_writer.send(close_frame(
+                    GatewayCloseCode::NotAuthenticated,
+                    "Not authenticated",
+                ));
+                break;
+            }
+        } else if let Some(idle_timeout_ms) = CONFIG.idle_timeout_ms {
+            // Any frame counts as activity, heartbeats included
+            if let Ok(next) = timeout(Duration::from_millis(idle_timeout_ms), stream.next()).await {
+                next
+            } else {
+                warn!("[{addr}] Client was idle for too long, disconnecting");
+                let _res = stream_writer.send(close_frame(
+                    GatewayCloseCode::SessionTimedOut,
+                    "Session timed out",
+                ));
+                break;
+            }
+        } else {
+            stream.next().await
+        };
+
+        let Some(Ok(msg)) = next else {
+            break;
+        };
+
         if !msg.is_text() && !msg.is_binary() {
             continue;
         }
 
+        bytes_received.increment(msg.as_payload().len() as u64);
+
+        let Some(payload) = encoding.decode(&msg) else {
+            warn!(
+                "[{addr}] Client sent a payload that isn't valid {}",
+                encoding.name()
+            );
+            continue;
+        };
+
         #[cfg(feature = "simd-json")]
-        let mut payload = unsafe { msg.as_text().unwrap_unchecked().to_owned() };
-        #[cfg(not(feature = "simd-json"))]
-        let payload = unsafe { msg.as_text().unwrap_unchecked() };
+        let mut payload = payload.to_owned();
 
         let Some(deserializer) = GatewayEvent::from_json(&payload) else {
             continue;
@@ -231,8 +1080,9 @@ pub async fn handle_client<S: 'static + AsyncRead + AsyncWrite + Unpin + Send>(
 
         match deserializer.op() {
             1 => {
+                *activity.last_heartbeat.lock().unwrap() = Some(Instant::now().into_std());
                 trace!("[{addr}] Sending heartbeat ACK");
-                let _res = stream_writer.send(Message::text(HEARTBEAT_ACK.to_string()));
+                activity.send(&stream_writer, Message::text(HEARTBEAT_ACK.to_string()));
             }
             2 => {
                 debug!("[{addr}] Client is identifying");
@@ -246,27 +1096,110 @@ pub async fn handle_client<S: 'static + AsyncRead + AsyncWrite + Unpin + Send>(
                     Ok(identify) => identify,
                     Err(e) => {
                         warn!("[{addr}] Invalid identify payload: {e:?}");
+                        metrics::counter!("gateway_client_invalid_identifies", "bot" => state.name.clone(), "reason" => json_error_category(&e))
+                            .increment(1);
+
+                        invalid_identifies += 1;
+
+                        if CONFIG
+                            .max_invalid_identifies
+                            .is_some_and(|max| invalid_identifies >= max)
+                        {
+                            warn!("[{addr}] Client sent {invalid_identifies} invalid identify payloads, disconnecting");
+                            let _res = stream_writer
+                                .send(close_frame(GatewayCloseCode::DecodeError, "Decode error"));
+                            break;
+                        }
+
                         continue;
                     }
                 };
 
-                let (shard_id, shard_count) = (identify.d.shard[0], identify.d.shard[1]);
-
-                if shard_count != state.shard_count {
-                    warn!("[{addr}] Shard count from client identify mismatched, disconnecting",);
+                // The shard array comes straight from the client, so don't trust its length
+                let &[shard_id, shard_count] = identify.d.shard.as_slice() else {
+                    warn!("[{addr}] Client sent an invalid shard array, disconnecting");
+                    let _res = stream_writer
+                        .send(close_frame(GatewayCloseCode::DecodeError, "Invalid shard"));
                     break;
-                }
+                };
 
                 if shard_id >= shard_count {
                     warn!("[{addr}] Shard ID from client is out of range, disconnecting",);
+                    let _res = stream_writer
+                        .send(close_frame(GatewayCloseCode::InvalidShard, "Invalid shard"));
                     break;
                 }
 
-                // Discord tokens may be prefixed by 'Bot ' in IDENTIFY
-                if CONFIG.validate_token
-                    && identify.d.token.split_whitespace().last() != Some(&CONFIG.token)
+                // The guilds of a client's shard are all on one shard of the proxy if
+                // the client runs a multiple of the proxy's shard count
+                let shard_id = if shard_count == state.shard_count {
+                    shard_id
+                } else if CONFIG.allow_shard_count_multiples
+                    && shard_count.is_multiple_of(state.shard_count)
                 {
+                    let proxy_shard_id = shard_id % state.shard_count;
+                    debug!("[{addr}] Mapping shard {shard_id} of {shard_count} to shard {proxy_shard_id} of the proxy");
+                    proxy_shard_id
+                } else {
+                    warn!(
+                        "[{addr}] Shard count from client identify mismatched (client: {shard_count}, proxy: {}), disconnecting",
+                        state.shard_count
+                    );
+                    metrics::counter!("gateway_client_shard_count_mismatches", "bot" => state.name.clone())
+                        .increment(1);
+                    let _res = stream_writer
+                        .send(close_frame(GatewayCloseCode::InvalidShard, "Invalid shard"));
+                    break;
+                };
+
+                // Discord tokens may be prefixed by 'Bot ' in IDENTIFY
+                let token_owner = identify
+                    .d
+                    .token
+                    .split_whitespace()
+                    .last()
+                    .and_then(|token| state.token_owner(token));
+
+                if CONFIG.validate_token && token_owner.is_none() {
                     warn!("[{addr}] Token from client mismatched, disconnecting");
+                    let _res = stream_writer.send(close_frame(
+                        GatewayCloseCode::AuthenticationFailed,
+                        "Authentication failed",
+                    ));
+                    break;
+                }
+
+                if let Some(TokenOwner::Consumer(name)) = token_owner {
+                    info!("[{addr}] Identifying with the token of consumer {name}");
+                }
+
+                // Only a range of shards might be running in this proxy
+                let Some(shard) = state.shard(shard_id) else {
+                    warn!("[{addr}] Shard ID {shard_id} from client is not running in this proxy, disconnecting");
+                    let _res = stream_writer
+                        .send(close_frame(GatewayCloseCode::InvalidShard, "Invalid shard"));
+                    break;
+                };
+
+                // The shard won't connect to Discord again, so the client would
+                // wait for READY forever
+                if let Some(code) = shard.failed() {
+                    warn!(
+                        "[{addr}] Shard {shard_id} failed with code {}, disconnecting",
+                        code as u16
+                    );
+                    let _res = stream_writer.send(close_frame(code, "Shard failed"));
+                    break;
+                }
+
+                if CONFIG
+                    .allowed_client_shards
+                    .as_ref()
+                    .is_some_and(|allowed| !allowed.contains(shard_id))
+                {
+                    warn!("[{addr}] Shard ID {shard_id} from client is not in allowed_client_shards, disconnecting");
+                    let _res = stream_writer
+                        .send(close_frame(GatewayCloseCode::InvalidShard, "Invalid shard"));
                     break;
                 }
 
@@ -276,21 +1209,53 @@ pub async fn handle_client<S: 'static + AsyncRead + AsyncWrite + Unpin + Send>(
                 let session = Session {
                     shard_id,
                     compress: identify.d.compress,
+                    seq: Arc::default(),
+                    intents: identify
+                        .d
+                        .intents
+                        .filter(|_| CONFIG.filter_client_intents)
+                        .map(Intents::from_bits_truncate),
+                    replay_guilds: !identify.d.skip_guild_replay,
+                    cache_profile,
+                    history: (CONFIG.resume_buffer_size > 0).then(Arc::default),
+                    guild_ids: identify.d.guild_ids.map(Arc::new),
+                    owner: Arc::default(),
                 };
-                let session_id = state.create_session(session);
+                let session_id = state.create_session(session.clone());
+                client_session_id = Some(session_id.clone());
+                state.clients.identified(
+                    client_id,
+                    shard_id,
+                    identify.d.compress,
+                    identify.d.intents,
+                    session.seq.clone(),
+                );
 
                 // The client is connected to this shard, so prepare for sending commands to it
-                let shard = state.shards[shard_id as usize].clone();
-                shard_sender = Some(shard.sender.clone());
-
-                if let Some(sender) = compress_tx.take() {
-                    shard_forward_task = Some(tokio::spawn(forward_shard(
-                        session_id,
-                        shard,
-                        stream_writer.clone(),
-                        true,
-                        0,
-                    )));
+                shard_sender = Some(shard.clone());
+                shard_label = shard_id.to_string();
+                bytes_received =
+                    bytes_received_counter(&state, &shard_label, encoding, compression);
+
+                if let (Some(sender), Some(cached_request_rx)) =
+                    (compress_tx.take(), cached_request_rx.take())
+                {
+                    shard_forward_task = Some(session.attach(
+                        client_id,
+                        &stream_writer,
+                        session_taken_over(),
+                        || {
+                            tokio::spawn(forward_shard(
+                                session_id,
+                                shard,
+                                stream_writer.clone(),
+                                cached_request_rx,
+                                None,
+                                session.clone(),
+                                activity.clone(),
+                            ))
+                        },
+                    ));
 
                     let _res = sender.send(identify.d.compress);
                 }
@@ -312,41 +1277,179 @@ pub async fn handle_client<S: 'static + AsyncRead + AsyncWrite + Unpin + Send>(
                 };
 
                 // Discord tokens may be prefixed by 'Bot ' in RESUME
-                if CONFIG.validate_token
-                    && resume.d.token.split_whitespace().last() != Some(&CONFIG.token)
-                {
+                let token_owner = resume
+                    .d
+                    .token
+                    .split_whitespace()
+                    .last()
+                    .and_then(|token| state.token_owner(token));
+
+                if CONFIG.validate_token && token_owner.is_none() {
                     warn!("[{addr}] Token from client mismatched, disconnecting");
+                    let _res = stream_writer.send(close_frame(
+                        GatewayCloseCode::AuthenticationFailed,
+                        "Authentication failed",
+                    ));
                     break;
                 }
 
+                if let Some(TokenOwner::Consumer(name)) = token_owner {
+                    info!("[{addr}] Resuming with the token of consumer {name}");
+                }
+
                 // Find the shard that has the matching session ID
-                if let Some(session) = state.get_session(&resume.d.session_id) {
-                    let session_id = resume.d.session_id;
-                    debug!("[{addr}] Successfully resuming session {session_id}",);
+                if let Some((shard, session)) = state
+                    .get_session(&resume.d.session_id)
+                    .and_then(|session| Some((state.shard(session.shard_id)?, session)))
+                {
+                    if let Some(code) = shard.failed() {
+                        warn!(
+                            "[{addr}] Shard {} failed with code {}, disconnecting",
+                            shard.id, code as u16
+                        );
+                        let _res = stream_writer.send(close_frame(code, "Shard failed"));
+                        break;
+                    }
 
-                    let shard = state.shards[session.shard_id as usize].clone();
+                    // The client can't have seen a later sequence than the session
+                    // sent it. Sessions replaying events from the history check the
+                    // sequence themselves.
+                    if session.history.is_none()
+                        && resume.d.seq > session.seq.load(Ordering::Relaxed)
+                    {
+                        warn!(
+                            "[{addr}] Client resumed at sequence {}, which its session never sent",
+                            resume.d.seq
+                        );
+                        activity.send(&stream_writer, Message::text(INVALID_SESSION.to_string()));
+                        continue;
+                    }
 
-                    if let Some(sender) = compress_tx.take() {
-                        shard_forward_task = Some(tokio::spawn(forward_shard(
-                            session_id,
-                            shard.clone(),
-                            stream_writer.clone(),
-                            false,
-                            resume.d.seq,
-                        )));
+                    let session_id = resume.d.session_id;
+                    debug!("[{addr}] Successfully resuming session {session_id}",);
 
+                    client_session_id = Some(session_id.clone());
+
+                    if let (Some(sender), Some(cached_request_rx)) =
+                        (compress_tx.take(), cached_request_rx.take())
+                    {
+                        shard_sender = Some(shard.clone());
+                        shard_label = shard.id.to_string();
+                        bytes_received =
+                            bytes_received_counter(&state, &shard_label, encoding, compression);
+                        state.clients.identified(
+                            client_id,
+                            shard.id,
+                            session.compress,
+                            None,
+                            session.seq.clone(),
+                        );
                         let _res = sender.send(session.compress);
+
+                        // A previous connection of the session is closed before
+                        // the events it missed are looked up
+                        shard_forward_task = Some(session.attach(
+                            client_id,
+                            &stream_writer,
+                            session_taken_over(),
+                            || {
+                                tokio::spawn(forward_shard(
+                                    session_id,
+                                    shard,
+                                    stream_writer.clone(),
+                                    cached_request_rx,
+                                    Some(resume.d.seq),
+                                    session.clone(),
+                                    activity.clone(),
+                                ))
+                            },
+                        ));
                     } else {
-                        let _res = stream_writer.send(Message::text(INVALID_SESSION.to_string()));
+                        // This connection already serves a session and its compression
+                        // stream can't be restarted
+                        activity.send(&stream_writer, Message::text(INVALID_SESSION.to_string()));
                     }
                 } else {
-                    let _res = stream_writer.send(Message::text(INVALID_SESSION.to_string()));
+                    activity.send(&stream_writer, Message::text(INVALID_SESSION.to_string()));
                 }
             }
-            _ => {
-                if let Some(sender) = &shard_sender {
-                    trace!("[{addr}] Sending {payload:?} to Discord directly");
-                    let _res = sender.send(payload.to_string());
+            GUILD_REPLAY_OP => {
+                if shard_sender.is_none() {
+                    warn!("[{addr}] Client requested a guild replay before IDENTIFY");
+                    continue;
+                }
+
+                if ratelimit
+                    .as_mut()
+                    .is_some_and(|ratelimit| !ratelimit.try_acquire())
+                {
+                    warn!("[{addr}] Client is sending commands too fast, dropping guild replay");
+                    metrics::counter!("gateway_client_commands_ratelimited", "bot" => state.name.clone(), "shard" => shard_label.clone())
+                        .increment(1);
+                    continue;
+                }
+
+                debug!("[{addr}] Client requested a guild replay");
+                let _res = cached_request_tx.send(CachedRequest::Guilds);
+            }
+            7 => {
+                // Reconnect is only ever sent by Discord, a client sending it can only mean that
+                // it wants to be reconnected. 4000 allows it to resume afterwards.
+                debug!("[{addr}] Client requested a reconnect");
+                let _res = stream_writer.send(close_frame(
+                    GatewayCloseCode::UnknownError,
+                    "Reconnect requested",
+                ));
+                break;
+            }
+            op => {
+                if let Some(shard) = &shard_sender {
+                    if CONFIG.read_only {
+                        warn!("[{addr}] Proxy is read-only, dropping op {op}");
+                        metrics::counter!("gateway_client_commands_rejected", "bot" => state.name.clone(), "shard" => shard_label.clone())
+                            .increment(1);
+                        continue;
+                    }
+
+                    if op == 8 {
+                        if let Some(request) = cached_member_request(&payload, shard) {
+                            debug!("[{addr}] Answering member request from the cache");
+                            let _res = cached_request_tx.send(CachedRequest::Members(request));
+                            continue;
+                        }
+                    }
+
+                    if op == 4 {
+                        let session_id = client_session_id.as_deref().unwrap_or_default();
+
+                        if !claim_voice_state(addr, &payload, shard, session_id) {
+                            continue;
+                        }
+                    }
+
+                    if ratelimit
+                        .as_mut()
+                        .is_some_and(|ratelimit| !ratelimit.try_acquire())
+                    {
+                        warn!("[{addr}] Client is sending commands too fast, dropping op {op}");
+                        metrics::counter!("gateway_client_commands_ratelimited", "bot" => state.name.clone(), "shard" => shard_label.clone())
+                            .increment(1);
+                        continue;
+                    }
+
+                    if CONFIG.log_commands {
+                        info!(
+                            "[{addr}] Forwarding op {op} to shard {}: {}",
+                            shard.id,
+                            redact_tokens(&payload)
+                        );
+                    } else {
+                        trace!("[{addr}] Sending op {op} to Discord directly");
+                    }
+
+                    metrics::counter!("gateway_client_commands_forwarded", "bot" => state.name.clone(), "shard" => shard_label.clone())
+                        .increment(1);
+                    shard.commands.send(payload.to_string());
                 } else {
                     warn!("[{addr}] Client attempted to send payload before IDENTIFY",);
                 }
@@ -356,26 +1459,252 @@ pub async fn handle_client<S: 'static + AsyncRead + AsyncWrite + Unpin + Send>(
 
     debug!("[{addr}] Client disconnected");
 
-    sink_task.abort();
+    state.clients.remove(client_id);
+
+    // Clients reconnecting later have to claim their voice connections again,
+    // unless another connection already took over the session
+    if let (Some(shard), Some(session_id)) = (&shard_sender, &client_session_id) {
+        if state
+            .get_session(session_id)
+            .is_some_and(|session| session.detach(client_id))
+        {
+            shard.voice_owners.release_all(session_id);
+        }
+    }
 
     if let Some(shard_forward_task) = shard_forward_task {
         shard_forward_task.abort();
     }
 
+    // Give the sink a chance to send pending messages, such as close frames,
+    // once every sender to its queue is gone
+    drop(compress_tx);
+    drop(stream_writer);
+
+    let sink_abort_handle = sink_task.abort_handle();
+
+    if timeout(FLUSH_TIMEOUT, sink_task).await.is_err() {
+        sink_abort_handle.abort();
+    }
+
     Ok(())
 }
 
-fn handler(
+/// Sharding of a bot as served at `/shards`.
+#[derive(Serialize)]
+struct ShardsInfo {
+    shard_count: u32,
+    recommended_shard_count: u32,
+    max_concurrency: u16,
+    shards: Vec<ShardInfo>,
+}
+
+#[derive(Serialize)]
+struct ShardInfo {
+    id: u32,
+    ready: bool,
+    last_error: Option<ShardError>,
+    last_close: Option<ShardClose>,
+    /// Whether Discord closed the shard with a code it can't recover from.
+    failed: bool,
+}
+
+/// Health of a bot's shards, served at `/health`.
+#[derive(Serialize)]
+struct HealthInfo {
+    healthy: bool,
+    /// Shards that failed for good, which need a change in the config or the
+    /// developer portal.
+    failed_shards: Vec<u32>,
+}
+
+/// Code that a shard's connection to Discord was last closed with.
+#[derive(Serialize)]
+struct ShardClose {
+    code: u16,
+    /// Name of the code if Discord sent it, such as "Authentication Failed".
+    meaning: Option<String>,
+    /// Seconds since the connection was closed.
+    elapsed_secs: u64,
+}
+
+/// Most recent error of a shard's connection to Discord.
+#[derive(Serialize)]
+struct ShardError {
+    message: String,
+    /// Seconds since the error happened.
+    elapsed_secs: u64,
+}
+
+/// Create a response without a body.
+pub fn empty_response(status: StatusCode) -> Response<Full<Bytes>> {
+    Response::builder()
+        .status(status)
+        .body(Full::default())
+        .unwrap()
+}
+
+/// Remove the configured `route_prefix` from a request path.
+fn strip_route_prefix(path: &str) -> Option<&str> {
+    let path = path.strip_prefix(CONFIG.route_prefix.trim_end_matches('/'))?;
+
+    if path.is_empty() {
+        Some("/")
+    } else {
+        // Don't match /prefix-something for /prefix
+        path.starts_with('/').then_some(path)
+    }
+}
+
+fn is_websocket_upgrade(request: &Request<Incoming>) -> bool {
+    request
+        .headers()
+        .get(UPGRADE)
+        .and_then(|value| value.to_str().ok())
+        .is_some_and(|value| value.eq_ignore_ascii_case("websocket"))
+}
+
+fn metrics_response(
+    request: &Request<Incoming>,
+    metrics: &PrometheusHandle,
+) -> Response<Full<Bytes>> {
+    if request.method() != Method::GET {
+        return empty_response(StatusCode::METHOD_NOT_ALLOWED);
+    }
+
+    let rendered = metrics.render();
+
+    // Metrics with many labels are repetitive and compress well
+    if accepts_gzip(request) {
+        let mut encoder = GzEncoder::new(Vec::new(), Level::fast());
+
+        if let Ok(compressed) = encoder
+            .write_all(rendered.as_bytes())
+            .and_then(|()| encoder.finish())
+        {
+            return Response::builder()
+                .status(StatusCode::OK)
+                .header(CONTENT_ENCODING, "gzip")
+                .header(VARY, "Accept-Encoding")
+                .body(Full::from(compressed))
+                .unwrap();
+        }
+    }
+
+    Response::builder()
+        .status(StatusCode::OK)
+        .header(VARY, "Accept-Encoding")
+        .body(Full::from(rendered))
+        .unwrap()
+}
+
+/// Whether the `Accept-Encoding` header of a request allows a gzip response.
+fn accepts_gzip(request: &Request<Incoming>) -> bool {
+    request
+        .headers()
+        .get_all(ACCEPT_ENCODING)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .any(|encoding| {
+            let mut parts = encoding.split(';');
+            let name = parts.next().unwrap_or_default().trim();
+
+            // A quality of 0 means that the encoding is not acceptable
+            let rejected = parts.any(|param| {
+                param
+                    .trim()
+                    .strip_prefix("q=")
+                    .and_then(|quality| quality.parse::<f32>().ok())
+                    .is_some_and(|quality| quality <= 0.0)
+            });
+
+            name.eq_ignore_ascii_case("gzip") && !rejected
+        })
+}
+
+/// Respond to `/health` with the shards that failed, with HTTP 503 if there
+/// are any.
+fn health_response(state: &State) -> Response<Full<Bytes>> {
+    let failed_shards = state
+        .shards
+        .iter()
+        .filter(|shard| shard.failed().is_some())
+        .map(|shard| shard.id)
+        .collect::<Vec<_>>();
+
+    let health = HealthInfo {
+        healthy: failed_shards.is_empty(),
+        failed_shards,
+    };
+
+    let status = if health.healthy {
+        StatusCode::OK
+    } else {
+        StatusCode::SERVICE_UNAVAILABLE
+    };
+
+    Response::builder()
+        .status(status)
+        .header(CONTENT_TYPE, "application/json")
+        .body(Full::from(to_string(&health).unwrap()))
+        .unwrap()
+}
+
+#[cfg_attr(not(feature = "pprof"), allow(clippy::unused_async))]
+async fn handler(
     addr: SocketAddr,
     request: Request<Incoming>,
-    state: State,
+    proxy: &Proxy,
     metrics: &PrometheusHandle,
 ) -> Response<Full<Bytes>> {
-    match (request.method(), request.uri().path()) {
-        (&Method::GET, "/metrics") => Response::builder()
-            .status(StatusCode::OK)
-            .body(Full::from(metrics.render()))
-            .unwrap(),
+    let Some(path) = strip_route_prefix(request.uri().path()) else {
+        return empty_response(StatusCode::NOT_FOUND);
+    };
+
+    // Metrics of all bots are served together, labeled by bot name, unless
+    // they have their own port
+    if path == "/metrics" && CONFIG.metrics_port.is_none() {
+        return metrics_response(&request, metrics);
+    }
+
+    #[cfg(feature = "pprof")]
+    if path == "/debug/pprof/profile" {
+        return profiling::profile(&request).await;
+    }
+
+    // Routes for bots other than the default one are prefixed with /bot/{name}
+    let (state, path) = match path.strip_prefix("/bot/") {
+        Some(rest) => {
+            let (name, path) = rest
+                .find('/')
+                .map_or((rest, "/"), |index| rest.split_at(index));
+
+            let Some(state) = proxy.bots.get(name) else {
+                return empty_response(StatusCode::NOT_FOUND);
+            };
+
+            (state.clone(), path)
+        }
+        None => (proxy.default.clone(), path),
+    };
+
+    // The gateway is served at /, but websocket upgrades are accepted on any
+    // path for backwards compatibility
+    if is_websocket_upgrade(&request) {
+        let addr = upgrade::client_addr(addr, &request);
+        return upgrade::server(addr, request, state);
+    }
+
+    if let Some(path) = path.strip_prefix("/admin/") {
+        return admin::handler(&request, &state, path);
+    }
+
+    if let Some(client_id) = path.strip_prefix("/debug/client/") {
+        return admin::client(&request, &state, client_id);
+    }
+
+    match (request.method(), path) {
         (&Method::GET, "/shard-count") => {
             let mut buffer = itoa::Buffer::new();
             let shard_count_str = buffer.format(state.shard_count);
@@ -385,13 +1714,58 @@ fn handler(
                 .body(Full::from(shard_count_str.to_string()))
                 .unwrap()
         }
-        // Usually one would return a 404 here, but we will just provide the websocket
-        // upgrade for backwards compatibility.
-        _ => upgrade::server(addr, request, state),
+        (&Method::GET, "/shards") => {
+            let shards = ShardsInfo {
+                shard_count: state.shard_count,
+                recommended_shard_count: state.recommended_shard_count,
+                max_concurrency: state.max_concurrency,
+                shards: state
+                    .shards
+                    .iter()
+                    .map(|shard| ShardInfo {
+                        id: shard.id,
+                        ready: shard.ready.is_ready(),
+                        last_error: shard.last_error.lock().unwrap().as_ref().map(
+                            |(at, message)| ShardError {
+                                message: message.clone(),
+                                elapsed_secs: at.elapsed().as_secs(),
+                            },
+                        ),
+                        last_close: shard.last_close_code.lock().unwrap().map(|(at, code)| {
+                            ShardClose {
+                                code,
+                                meaning: (code >= 4000).then(|| dispatch::close_code_meaning(code)),
+                                elapsed_secs: at.elapsed().as_secs(),
+                            }
+                        }),
+                        failed: shard.failed().is_some(),
+                    })
+                    .collect(),
+            };
+
+            Response::builder()
+                .status(StatusCode::OK)
+                .header(CONTENT_TYPE, "application/json")
+                .body(Full::from(to_string(&shards).unwrap()))
+                .unwrap()
+        }
+        (&Method::GET, "/health") => health_response(&state),
+        (&Method::GET, "/clients") => admin::clients(&request, &state),
+        (&Method::GET, "/config") => admin::config(&request, &state),
+        (_, "/shard-count" | "/shards" | "/health" | "/clients" | "/config") => {
+            empty_response(StatusCode::METHOD_NOT_ALLOWED)
+        }
+        // The gateway can only be used with a websocket upgrade
+        (_, "/") => empty_response(StatusCode::BAD_REQUEST),
+        _ => empty_response(StatusCode::NOT_FOUND),
     }
 }
 
-pub async fn run(port: u16, state: State, metrics_handle: PrometheusHandle) -> Result<(), Error> {
+pub async fn run(
+    port: u16,
+    proxy: Arc<Proxy>,
+    metrics_handle: PrometheusHandle,
+) -> Result<(), Error> {
     let ip = IpAddr::from_str("::").unwrap();
     let addr: SocketAddr = (ip, port).into();
 
@@ -416,7 +1790,7 @@ pub async fn run(port: u16, state: State, metrics_handle: PrometheusHandle) -> R
 
         trace!("[{addr:?}] New connection");
 
-        let state = state.clone();
+        let proxy = proxy.clone();
         let metrics_handle = metrics_handle.clone();
 
         tokio::spawn(async move {
@@ -424,12 +1798,14 @@ pub async fn run(port: u16, state: State, metrics_handle: PrometheusHandle) -> R
                 .serve_connection_with_upgrades(
                     TokioIo::new(conn),
                     service_fn(move |incoming: Request<Incoming>| {
-                        ready(Ok::<_, Infallible>(handler(
-                            addr,
-                            incoming,
-                            state.clone(),
-                            &metrics_handle,
-                        )))
+                        let proxy = proxy.clone();
+                        let metrics_handle = metrics_handle.clone();
+
+                        async move {
+                            Ok::<_, Infallible>(
+                                handler(addr, incoming, &proxy, &metrics_handle).await,
+                            )
+                        }
                     }),
                 )
                 .await
@@ -439,3 +1815,364 @@ pub async fn run(port: u16, state: State, metrics_handle: PrometheusHandle) -> R
         });
     }
 }
+
+/// Respond to requests on the metrics port, which serves the metrics and the
+/// health of each bot at the same paths as the gateway port.
+fn metrics_port_handler(
+    request: &Request<Incoming>,
+    proxy: &Proxy,
+    metrics: &PrometheusHandle,
+) -> Response<Full<Bytes>> {
+    let path = request.uri().path();
+
+    if path == "/metrics" {
+        return metrics_response(request, metrics);
+    }
+
+    let state = if path == "/health" {
+        &proxy.default
+    } else if let Some(state) = path
+        .strip_prefix("/bot/")
+        .and_then(|rest| rest.strip_suffix("/health"))
+        .and_then(|name| proxy.bots.get(name))
+    {
+        state
+    } else {
+        return empty_response(StatusCode::NOT_FOUND);
+    };
+
+    if request.method() == Method::GET {
+        health_response(state)
+    } else {
+        empty_response(StatusCode::METHOD_NOT_ALLOWED)
+    }
+}
+
+/// Serve only the metrics and health checks on a separate port, so that they
+/// don't have to be exposed together with the gateway.
+pub async fn run_metrics(port: u16, proxy: Arc<Proxy>, metrics_handle: PrometheusHandle) {
+    let ip = IpAddr::from_str("::").unwrap();
+    let addr: SocketAddr = (ip, port).into();
+
+    let listener = match TcpListener::bind(addr).await {
+        Ok(listener) => listener,
+        Err(e) => {
+            error!("Failed to bind metrics TCP listener: {e}");
+            return;
+        }
+    };
+
+    info!("Serving metrics on {addr}");
+
+    loop {
+        let conn = match listener.accept().await {
+            Ok((stream, _)) => stream,
+            Err(e) => {
+                error!("Failed to accept metrics connection: {e}");
+                return;
+            }
+        };
+
+        let proxy = proxy.clone();
+        let metrics_handle = metrics_handle.clone();
+
+        tokio::spawn(async move {
+            if let Err(e) = auto::Builder::new(TokioExecutor::new())
+                .serve_connection(
+                    TokioIo::new(conn),
+                    service_fn(move |incoming: Request<Incoming>| {
+                        let response = metrics_port_handler(&incoming, &proxy, &metrics_handle);
+
+                        ready(Ok::<_, Infallible>(response))
+                    }),
+                )
+                .await
+            {
+                error!("Error handling metrics connection: {e}");
+            }
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use flate2::{Decompress, FlushDecompress};
+    use futures_util::{sink, SinkExt, StreamExt};
+    use tokio::{
+        io::{duplex, DuplexStream},
+        sync::{
+            mpsc::{unbounded_channel, UnboundedSender},
+            oneshot, watch,
+        },
+    };
+    use tokio_websockets::{ClientBuilder, Error, Message, WebSocketStream};
+    use twilight_gateway::Intents;
+
+    use std::{
+        collections::HashMap,
+        net::SocketAddr,
+        sync::{Arc, RwLock},
+    };
+
+    use super::{
+        close_reason, handle_client, is_sequence_at, rewrite_sequence, sink_from_queue,
+        MAX_CLOSE_REASON_LEN, RESUMED,
+    };
+    use crate::{
+        compression::Compression,
+        config::CacheProfile,
+        deserializer::{GatewayEvent, SequenceInfo},
+        dispatch::{BroadcastMessage, Dispatcher},
+        encoding::Encoding,
+        state::{Clients, Inner, Shard, State},
+    };
+
+    const EVENT: &str = r#"{"t":"MESSAGE_CREATE","s":1,"op":0,"d":{}}"#;
+    const SEQUENCED: &str = r#"{"t":"MESSAGE_CREATE","s":9,"op":0,"d":{"content":"s"}}"#;
+    const READY: &str =
+        r#"{"t":"READY","s":1,"op":0,"d":{"v":10,"session_id":"session","guilds":[]}}"#;
+
+    /// Write messages to a client connection until its queue is drained and
+    /// return what was sent over the websocket.
+    async fn sent_messages(
+        compression: Option<Compression>,
+        compress: Option<bool>,
+        messages: &[&str],
+    ) -> Vec<Message> {
+        let (queue_tx, queue_rx) = unbounded_channel();
+        let (compress_tx, compress_rx) = oneshot::channel();
+        let (sent_tx, mut sent_rx) = unbounded_channel();
+
+        for &message in messages {
+            queue_tx.send(Message::text(message.to_owned())).unwrap();
+        }
+
+        drop(queue_tx);
+        compress_tx.send(compress).unwrap();
+
+        let sink = Box::pin(sink::unfold(
+            sent_tx,
+            |sent_tx: UnboundedSender<Message>, message: Message| async move {
+                let _res = sent_tx.send(message);
+                Ok::<_, Error>(sent_tx)
+            },
+        ));
+
+        sink_from_queue(
+            SocketAddr::from(([127, 0, 0, 1], 0)),
+            compression,
+            true,
+            compress_rx,
+            Arc::default(),
+            queue_rx,
+            sink,
+        )
+        .await
+        .unwrap();
+
+        let mut sent = Vec::new();
+
+        while let Ok(message) = sent_rx.try_recv() {
+            sent.push(message);
+        }
+
+        sent
+    }
+
+    /// Inflate the messages of a connection like a client does, with a single
+    /// decompressor for all of them.
+    fn inflate(messages: &[Message]) -> Vec<String> {
+        let mut decompress = Decompress::new(true);
+
+        messages
+            .iter()
+            .map(|message| {
+                let mut inflated = Vec::with_capacity(4096);
+                decompress
+                    .decompress_vec(message.as_payload(), &mut inflated, FlushDecompress::Sync)
+                    .unwrap();
+
+                String::from_utf8(inflated).unwrap()
+            })
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn resumed_connection_continues_one_compression_stream() {
+        let first = inflate(&sent_messages(Some(Compression::Zlib), None, &[EVENT]).await);
+        assert_eq!(first[1..], [EVENT]);
+
+        // The client resumes on a new connection with a new decompressor, which
+        // inflates HELLO, RESUMED and the replayed events as one stream
+        let resumed =
+            inflate(&sent_messages(Some(Compression::Zlib), None, &[RESUMED, EVENT]).await);
+        assert!(resumed[0].contains(r#""op":10"#));
+        assert_eq!(resumed[1..], [RESUMED, EVENT]);
+    }
+
+    #[tokio::test]
+    async fn resumed_session_compression_starts_after_hello() {
+        // Compression requested in the session's IDENTIFY only applies after
+        // the HELLO of the new connection
+        let messages = sent_messages(None, Some(true), &[RESUMED, EVENT]).await;
+        assert!(messages[0].is_text());
+        assert_eq!(inflate(&messages[1..]), [RESUMED, EVENT]);
+    }
+
+    fn sequence_info(payload: &str) -> SequenceInfo {
+        GatewayEvent::from_json(payload)
+            .and_then(|event| event.into_parts().1)
+            .unwrap()
+    }
+
+    #[test]
+    fn rewrites_sequence_at_range() {
+        let mut payload = SEQUENCED.to_owned();
+        rewrite_sequence(0, &mut payload, sequence_info(SEQUENCED), "3");
+
+        assert_eq!(payload, SEQUENCED.replace(r#""s":9"#, r#""s":3"#));
+    }
+
+    #[test]
+    fn rewrites_sequence_with_more_digits() {
+        let mut payload = SEQUENCED.to_owned();
+        rewrite_sequence(0, &mut payload, sequence_info(SEQUENCED), "1000");
+
+        assert_eq!(payload, SEQUENCED.replace(r#""s":9"#, r#""s":1000"#));
+        assert_eq!(sequence_info(&payload).0, 1000);
+    }
+
+    #[test]
+    fn finds_sequence_for_stale_range() {
+        let stale = SequenceInfo(9, 0..2);
+        assert!(!is_sequence_at(SEQUENCED, &stale.1, 9));
+
+        let mut payload = SEQUENCED.to_owned();
+        rewrite_sequence(0, &mut payload, stale, "3");
+
+        assert_eq!(payload, SEQUENCED.replace(r#""s":9"#, r#""s":3"#));
+    }
+
+    #[test]
+    fn keeps_payload_with_mismatched_sequence() {
+        let SequenceInfo(_, range) = sequence_info(SEQUENCED);
+        assert!(is_sequence_at(SEQUENCED, &range, 9));
+        assert!(!is_sequence_at(SEQUENCED, &range, 8));
+
+        let mut payload = SEQUENCED.to_owned();
+        rewrite_sequence(0, &mut payload, SequenceInfo(8, range), "3");
+
+        assert_eq!(payload, SEQUENCED);
+    }
+
+    /// Create a bot with the token `token` and a single shard that never
+    /// connects to Discord.
+    fn bot_state() -> State {
+        Arc::new(Inner {
+            name: String::from("test"),
+            token: watch::channel(String::from("token")).0,
+            consumer_tokens: HashMap::new(),
+            shards: vec![Arc::new(Shard::disconnected(0))],
+            intents: Intents::empty(),
+            shard_count: 1,
+            recommended_shard_count: 1,
+            max_concurrency: 1,
+            sessions: RwLock::default(),
+            clients: Clients::default(),
+        })
+    }
+
+    /// Connect a client to the proxy over an in-memory stream and identify
+    /// with a token after receiving HELLO.
+    async fn identified_client(state: State, token: &str) -> WebSocketStream<DuplexStream> {
+        let (client_io, server_io) = duplex(4096);
+        tokio::spawn(handle_client(
+            SocketAddr::from(([127, 0, 0, 1], 0)),
+            server_io,
+            state,
+            None,
+            true,
+            Encoding::Json,
+            &CacheProfile::ALL,
+        ));
+
+        let mut client = ClientBuilder::new().take_over(client_io);
+        let hello = client.next().await.unwrap().unwrap();
+        assert!(hello.as_text().unwrap().contains(r#""op":10"#));
+
+        client
+            .send(Message::text(format!(
+                r#"{{"op":2,"d":{{"token":"Bot {token}","intents":0,"shard":[0,1]}}}}"#
+            )))
+            .await
+            .unwrap();
+
+        client
+    }
+
+    /// Receive messages until the connection is closed and return the close
+    /// code and reason.
+    async fn close_of(client: &mut WebSocketStream<DuplexStream>) -> (u16, String) {
+        loop {
+            let message = client.next().await.unwrap().unwrap();
+
+            if let Some((code, reason)) = message.as_close() {
+                return (u16::from(code), reason.to_owned());
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn closes_client_with_wrong_token() {
+        let mut client = identified_client(bot_state(), "wrong").await;
+
+        let (code, reason) = close_of(&mut client).await;
+        assert_eq!(code, 4004);
+        assert!(reason.contains("Authentication failed"));
+    }
+
+    #[tokio::test]
+    async fn closes_clients_when_token_changes() {
+        let state = bot_state();
+        let shard = state.shards[0].clone();
+        let mut client = identified_client(state, "token").await;
+
+        Dispatcher::new(shard.clone()).handle(READY.to_owned());
+
+        // Clients subscribe to the shard's events before they get READY
+        let ready = client.next().await.unwrap().unwrap();
+        assert!(ready.as_text().unwrap().contains(r#""t":"READY""#));
+
+        let _res = shard.events.send(BroadcastMessage::TokenChanged);
+
+        let (code, reason) = close_of(&mut client).await;
+        assert_eq!(code, 4004);
+        assert!(reason.contains("Token changed"));
+    }
+
+    #[test]
+    fn serializes_close_reason_with_backoff() {
+        assert_eq!(close_reason("Rate limited", None), "Rate limited");
+        assert_eq!(
+            close_reason("Rate limited", Some(5000)),
+            r#"{"message":"Rate limited","retry_after_ms":5000}"#
+        );
+
+        // Reasons are escaped instead of breaking the JSON
+        assert_eq!(
+            close_reason(r#"Say "hi""#, Some(0)),
+            r#"{"message":"Say \"hi\"","retry_after_ms":0}"#
+        );
+    }
+
+    #[test]
+    fn falls_back_to_bare_close_reason_when_too_long() {
+        let reason = "a".repeat(100);
+        assert_eq!(close_reason(&reason, Some(5000)), reason);
+
+        let reason = "ä".repeat(100);
+        let truncated = close_reason(&reason, None);
+        assert!(truncated.len() <= MAX_CLOSE_REASON_LEN);
+        assert!(reason.starts_with(&*truncated));
+    }
+}
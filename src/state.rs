@@ -1,17 +1,46 @@
 use rand::{distributions::Alphanumeric, thread_rng, Rng};
-use tokio::sync::{broadcast, Notify};
-use twilight_gateway::MessageSender;
+use tokio::{
+    sync::{broadcast, mpsc::UnboundedSender, watch, Notify},
+    task::{AbortHandle, JoinHandle},
+};
+use tokio_websockets::Message;
+#[cfg(test)]
+use twilight_cache_inmemory::InMemoryCache;
+#[cfg(test)]
+use twilight_gateway::ShardId;
+use twilight_gateway::{error::ChannelError, CloseFrame, MessageSender};
+use twilight_model::{
+    gateway::{CloseCode, Intents},
+    id::{marker::GuildMarker, Id},
+};
 
 use std::{
-    collections::HashMap,
-    sync::{Arc, RwLock},
+    collections::{HashMap, HashSet, VecDeque},
+    iter,
+    net::SocketAddr,
+    sync::{
+        atomic::{AtomicU64, AtomicUsize, Ordering},
+        Arc, Mutex, RwLock,
+    },
+    time::{Duration, Instant},
 };
 
-use crate::{cache, dispatch::BroadcastMessage, model::JsonObject};
+use crate::{
+    cache,
+    compression::Compression,
+    config::{CacheProfile, CONFIG},
+    dispatch::{BroadcastEvent, BroadcastMessage},
+    encoding::Encoding,
+    model::JsonObject,
+    ratelimit::CommandQueue,
+};
 
 /// Manager for the READY state of a shard.
 pub struct Ready {
     inner: RwLock<Option<JsonObject>>,
+    /// READY of the shard's first session as received from Discord, until it
+    /// is taken by the first client.
+    first: Mutex<Option<JsonObject>>,
     changed: Notify,
 }
 
@@ -19,34 +48,39 @@ impl Ready {
     pub fn new() -> Self {
         Self {
             inner: RwLock::new(None),
+            first: Mutex::new(None),
             changed: Notify::new(),
         }
     }
 
-    pub async fn wait_changed(&self) {
-        self.changed.notified().await;
-    }
-
     pub fn is_ready(&self) -> bool {
         self.inner.read().unwrap().is_some()
     }
 
-    pub fn set_ready(&self, payload: JsonObject) {
-        *self.inner.write().unwrap() = Some(payload);
+    /// Set the READY of the current session, along with the READY from Discord
+    /// to give to the first client, if it should get it. Both are written
+    /// before anyone waiting is woken up, so the first client can't miss it.
+    pub fn set_ready(&self, payload: JsonObject, first: Option<JsonObject>) {
+        let mut inner = self.inner.write().unwrap();
+        *self.first.lock().unwrap() = first;
+        *inner = Some(payload);
+        drop(inner);
+
         self.changed.notify_waiters();
     }
 
     pub fn set_not_ready(&self) {
-        *self.inner.write().unwrap() = None;
+        let mut inner = self.inner.write().unwrap();
+        *self.first.lock().unwrap() = None;
+        *inner = None;
+        drop(inner);
+
         self.changed.notify_waiters();
     }
 
-    pub async fn wait_until_ready(&self) -> JsonObject {
-        while !self.is_ready() {
-            self.wait_changed().await;
-        }
-
-        self.inner.read().unwrap().clone().unwrap()
+    /// Take the READY from Discord, if no client has gotten it yet.
+    pub fn take_first(&self) -> Option<JsonObject> {
+        self.first.lock().unwrap().take()
     }
 }
 
@@ -54,14 +88,325 @@ impl Ready {
 pub struct Shard {
     /// ID of this shard.
     pub id: u32,
+    /// Name of the bot this shard belongs to.
+    pub bot: String,
     /// Sender for this shard.
-    pub sender: MessageSender,
+    pub sender: Arc<ShardSender>,
+    /// Queue for commands sent to Discord, which should be used instead of
+    /// sending them directly.
+    pub commands: CommandQueue,
     /// Handle for broadcasting events for this shard.
     pub events: broadcast::Sender<BroadcastMessage>,
     /// READY state manager for this shard.
     pub ready: Ready,
     /// Cache for guilds on this shard.
     pub guilds: cache::Guilds,
+    /// Sessions owning the voice connections on this shard.
+    pub voice_owners: VoiceOwners,
+    /// Most recent error of the connection to Discord and when it happened.
+    pub last_error: Mutex<Option<(Instant, String)>>,
+    /// Code that the connection to Discord was last closed with and when.
+    pub last_close_code: Mutex<Option<(Instant, u16)>>,
+    /// Close code that the shard failed with for good, if any.
+    pub failed: Mutex<Option<CloseCode>>,
+    /// Recent events, replayed to clients that resume.
+    pub history: EventHistory,
+}
+
+impl Shard {
+    /// Remember an error of the connection to Discord, replacing the previous
+    /// one.
+    pub fn set_last_error(&self, error: String) {
+        metrics::counter!("gateway_shard_errors", "bot" => self.bot.clone(), "shard" => self.id.to_string())
+            .increment(1);
+
+        *self.last_error.lock().unwrap() = Some((Instant::now(), error));
+    }
+
+    /// Remember the code that the connection to Discord was closed with.
+    pub fn set_close_code(&self, code: u16) {
+        metrics::counter!("gateway_shard_disconnects", "bot" => self.bot.clone(), "shard" => self.id.to_string(), "code" => code.to_string())
+            .increment(1);
+
+        *self.last_close_code.lock().unwrap() = Some((Instant::now(), code));
+    }
+
+    /// Mark the shard as failed because Discord closed it with a code that
+    /// reconnecting won't help with, and disconnect its clients.
+    pub fn set_failed(&self, code: CloseCode) {
+        *self.failed.lock().unwrap() = Some(code);
+        let _res = self.events.send(BroadcastMessage::Failed(code));
+
+        // Clients waiting for a READY would otherwise wait forever
+        self.ready.changed.notify_waiters();
+    }
+
+    /// Close code that the shard failed with, if it can't connect anymore.
+    pub fn failed(&self) -> Option<CloseCode> {
+        *self.failed.lock().unwrap()
+    }
+
+    /// Wait until the shard has a READY, or return the close code it failed
+    /// with if it won't get one anymore.
+    pub async fn wait_until_ready(&self) -> Result<JsonObject, CloseCode> {
+        loop {
+            // Register for notifications before checking the state, otherwise
+            // a READY set in between would be missed
+            let changed = self.ready.changed.notified();
+
+            if let Some(code) = self.failed() {
+                return Err(code);
+            }
+
+            let payload = self.ready.inner.read().unwrap().clone();
+
+            if let Some(payload) = payload {
+                return Ok(payload);
+            }
+
+            changed.await;
+        }
+    }
+
+    /// Close the shard's connection to Discord and start a new session, leaving
+    /// the other shards alone. Clients stay connected and are resynced once the
+    /// new READY arrives.
+    pub fn reconnect(&self) {
+        // Clients connecting in the meantime wait for the new READY
+        self.ready.set_not_ready();
+        let _res = self.sender.close(CloseFrame::NORMAL);
+    }
+}
+
+#[cfg(test)]
+impl Shard {
+    /// Create a shard that never connects to Discord, so that payloads can be
+    /// handed to its dispatcher directly. Commands sent to it are dropped.
+    pub fn disconnected(id: u32) -> Self {
+        let shard =
+            twilight_gateway::Shard::new(ShardId::new(id, id + 1), String::new(), Intents::empty());
+        let sender = Arc::new(ShardSender::new(shard.sender()));
+        let cache = InMemoryCache::builder()
+            .resource_types(CONFIG.cache.clone().into())
+            .build();
+
+        Self {
+            id,
+            bot: String::from("test"),
+            sender: sender.clone(),
+            commands: CommandQueue::new(sender, String::from("test"), id),
+            events: broadcast::channel(CONFIG.backpressure).0,
+            ready: Ready::new(),
+            guilds: cache::Guilds::new(Arc::new(cache)),
+            voice_owners: VoiceOwners::default(),
+            last_error: Mutex::default(),
+            last_close_code: Mutex::default(),
+            failed: Mutex::default(),
+            history: EventHistory::new(CONFIG.resume_buffer_size),
+        }
+    }
+}
+
+/// Recent events of a shard, kept so that clients resuming their session get
+/// the events they missed while they were disconnected.
+pub struct EventHistory {
+    inner: Mutex<HistoryInner>,
+    /// Amount of events kept, nothing is kept if this is 0.
+    capacity: usize,
+}
+
+struct HistoryInner {
+    events: VecDeque<BroadcastEvent>,
+    /// Index of the next event.
+    next_index: u64,
+}
+
+impl EventHistory {
+    pub const fn new(capacity: usize) -> Self {
+        Self {
+            inner: Mutex::new(HistoryInner {
+                events: VecDeque::new(),
+                next_index: 0,
+            }),
+            capacity,
+        }
+    }
+
+    /// Assign the next index to an event and keep a copy of it, dropping the
+    /// oldest event once the history is full.
+    pub fn push(&self, mut event: BroadcastEvent) -> BroadcastEvent {
+        let mut inner = self.inner.lock().unwrap();
+        event.index = inner.next_index;
+        inner.next_index += 1;
+
+        if self.capacity > 0 {
+            if inner.events.len() == self.capacity {
+                inner.events.pop_front();
+            }
+
+            inner.events.push_back(event.clone());
+        }
+
+        event
+    }
+
+    /// Index that the next event will get.
+    pub fn next_index(&self) -> u64 {
+        self.inner.lock().unwrap().next_index
+    }
+
+    /// Get the events from an index on, unless some of them were dropped.
+    pub fn since(&self, index: u64) -> Option<Vec<BroadcastEvent>> {
+        let inner = self.inner.lock().unwrap();
+        let first_index = inner
+            .events
+            .front()
+            .map_or(inner.next_index, |event| event.index);
+
+        if index < first_index {
+            return None;
+        }
+
+        Some(
+            inner
+                .events
+                .iter()
+                .skip_while(|event| event.index < index)
+                .cloned()
+                .collect(),
+        )
+    }
+}
+
+/// Where a session is in its shard's history, shared between the connections
+/// of the session like its sequence.
+#[derive(Default)]
+pub struct HistoryPosition {
+    /// Index of the first event that the session hasn't seen yet.
+    next_index: u64,
+    /// Sequence and history index of the events sent to the client, as many as
+    /// the history keeps.
+    sent: VecDeque<(usize, u64)>,
+}
+
+impl HistoryPosition {
+    /// Continue with the next event of the history.
+    pub const fn start_at(&mut self, index: u64) {
+        self.next_index = index;
+    }
+
+    /// Whether the session already saw an event.
+    pub const fn has_seen(&self, index: u64) -> bool {
+        index < self.next_index
+    }
+
+    /// Record an event that wasn't sent to the client.
+    pub const fn skipped(&mut self, index: u64) {
+        self.next_index = index + 1;
+    }
+
+    /// Record an event that was sent to the client with a sequence.
+    pub fn sent(&mut self, seq: usize, index: u64, capacity: usize) {
+        self.next_index = index + 1;
+
+        if self.sent.len() == capacity {
+            self.sent.pop_front();
+        }
+
+        self.sent.push_back((seq, index));
+    }
+
+    /// Find the first event to replay to a client resuming after `resume_seq`
+    /// while the session is at `seq`. Everything the client missed has to be
+    /// an event from the history, otherwise it can't be replayed. A client
+    /// that is ahead of the session can't resume either.
+    pub fn resume(&mut self, resume_seq: usize, seq: usize) -> Option<u64> {
+        let missed = seq.checked_sub(resume_seq)?;
+
+        if missed > 0 {
+            let first = self.sent.len().checked_sub(missed)?;
+            let (first_seq, first_index) = self.sent[first];
+
+            if first_seq != resume_seq + 1 {
+                return None;
+            }
+
+            // The events are sent again with the same sequences
+            self.sent.truncate(first);
+            self.next_index = first_index;
+        }
+
+        Some(self.next_index)
+    }
+}
+
+/// Sender of a shard's connection to Discord. The shard is replaced when the
+/// token changes, so the sender is swapped out along with it.
+pub struct ShardSender(RwLock<MessageSender>);
+
+impl ShardSender {
+    pub const fn new(sender: MessageSender) -> Self {
+        Self(RwLock::new(sender))
+    }
+
+    /// Send a raw command to Discord.
+    pub fn send(&self, command: String) -> Result<(), ChannelError> {
+        self.0.read().unwrap().send(command)
+    }
+
+    /// Close the connection to Discord. Twilight reconnects afterwards unless
+    /// the frame is fatal.
+    pub fn close(&self, close_frame: CloseFrame<'static>) -> Result<(), ChannelError> {
+        self.0.read().unwrap().close(close_frame)
+    }
+
+    /// Use the sender of a new shard from now on.
+    pub fn replace(&self, sender: MessageSender) {
+        *self.0.write().unwrap() = sender;
+    }
+}
+
+/// Tracker for which session owns the voice connection in a guild, so that
+/// clients sharing a shard don't take over each other's voice connections.
+#[derive(Default)]
+pub struct VoiceOwners(Mutex<HashMap<Id<GuildMarker>, String>>);
+
+impl VoiceOwners {
+    /// Claim the voice connection in a guild for a session, or release it if
+    /// the session is leaving. Returns the owning session if it is another one.
+    pub fn claim(
+        &self,
+        guild_id: Id<GuildMarker>,
+        session_id: &str,
+        leaving: bool,
+    ) -> Option<String> {
+        let mut owners = self.0.lock().unwrap();
+
+        match owners.get(&guild_id) {
+            Some(owner) if owner != session_id => return Some(owner.clone()),
+            _ if leaving => {
+                owners.remove(&guild_id);
+            }
+            _ => {
+                owners.insert(guild_id, session_id.to_string());
+            }
+        }
+
+        None
+    }
+
+    /// Get the session owning the voice connection in a guild.
+    pub fn owner(&self, guild_id: Id<GuildMarker>) -> Option<String> {
+        self.0.lock().unwrap().get(&guild_id).cloned()
+    }
+
+    /// Release all voice connections owned by a session.
+    pub fn release_all(&self, session_id: &str) {
+        self.0
+            .lock()
+            .unwrap()
+            .retain(|_, owner| owner != session_id);
+    }
 }
 
 /// A session initiated by a client.
@@ -71,22 +416,299 @@ pub struct Session {
     pub shard_id: u32,
     /// Compression as requested in IDENTIFY.
     pub compress: Option<bool>,
+    /// Last sequence number sent to the client, shared between its connections
+    /// so that it continues after resuming.
+    pub seq: Arc<AtomicUsize>,
+    /// Intents from IDENTIFY that events are filtered by, if enabled.
+    pub intents: Option<Intents>,
+    /// Whether READY is followed by a `GUILD_CREATE` for every guild.
+    pub replay_guilds: bool,
+    /// What the replayed `GUILD_CREATE`s contain.
+    pub cache_profile: &'static CacheProfile,
+    /// Position in the shard's history, if it keeps events for resuming.
+    pub history: Option<Arc<Mutex<HistoryPosition>>>,
+    /// Guilds from IDENTIFY that events are filtered by, if any.
+    pub guild_ids: Option<Arc<HashSet<Id<GuildMarker>>>>,
+    /// Connection that the session is served on, shared between the clones of
+    /// the session so that a resume can take it over.
+    pub owner: Arc<Mutex<SessionOwner>>,
+}
+
+impl Session {
+    /// Serve the session on a connection, with the task spawned by `forward`.
+    /// The connection that served it before is sent `close` and stops
+    /// forwarding events first, so that only one connection at a time sends
+    /// the session's events and advances its sequence and history.
+    pub fn attach(
+        &self,
+        client_id: u64,
+        stream_writer: &UnboundedSender<Message>,
+        close: Message,
+        forward: impl FnOnce() -> JoinHandle<()>,
+    ) -> JoinHandle<()> {
+        let mut owner = self.owner.lock().unwrap();
+
+        if let SessionOwner::Attached(previous) = &*owner {
+            previous.forward_task.abort();
+            let _res = previous.stream_writer.send(close);
+        }
+
+        let forward_task = forward();
+
+        *owner = SessionOwner::Attached(SessionConnection {
+            client_id,
+            stream_writer: stream_writer.clone(),
+            forward_task: forward_task.abort_handle(),
+        });
+
+        forward_task
+    }
+
+    /// Record that a connection closed, returning whether it still served the
+    /// session, which it doesn't if another connection took it over.
+    pub fn detach(&self, client_id: u64) -> bool {
+        let mut owner = self.owner.lock().unwrap();
+
+        if matches!(&*owner, SessionOwner::Attached(connection) if connection.client_id == client_id)
+        {
+            *owner = SessionOwner::Detached(Instant::now());
+            true
+        } else {
+            false
+        }
+    }
+
+    /// Whether no connection served the session for longer than `timeout`.
+    pub fn is_expired(&self, timeout: Duration) -> bool {
+        let owner = self.owner.lock().unwrap();
+
+        matches!(&*owner, SessionOwner::Detached(since) if since.elapsed() > timeout)
+    }
+}
+
+/// Whether a session is served on a connection.
+pub enum SessionOwner {
+    /// Served on a connection, by its forwarding task.
+    Attached(SessionConnection),
+    /// Not served since the given time.
+    Detached(Instant),
+}
+
+impl Default for SessionOwner {
+    fn default() -> Self {
+        Self::Detached(Instant::now())
+    }
+}
+
+/// Connection that a session is served on.
+pub struct SessionConnection {
+    /// ID of the client in the registry.
+    client_id: u64,
+    /// Queue of the client's websocket, to close it when it is taken over.
+    stream_writer: UnboundedSender<Message>,
+    /// Task that forwards the shard's events to the client.
+    forward_task: AbortHandle,
+}
+
+/// A client connected to the proxy.
+#[derive(Clone)]
+pub struct ClientInfo {
+    /// Remote address of the client.
+    pub addr: SocketAddr,
+    /// When the client connected.
+    pub connected_at: Instant,
+    /// Transport compression requested in the query string.
+    pub compression: Option<Compression>,
+    /// Payload encoding requested in the query string.
+    pub encoding: Encoding,
+    /// Shard that the client identified or resumed for.
+    pub shard_id: Option<u32>,
+    /// Payload compression requested in IDENTIFY.
+    pub compress: Option<bool>,
+    /// Intents sent in IDENTIFY. The proxy doesn't use them, but they show
+    /// what the client expects to receive.
+    pub intents: Option<u64>,
+    /// Sequence of the client's session, once it identified or resumed.
+    pub seq: Option<Arc<AtomicUsize>>,
+    /// Values that change with every message, updated without going through
+    /// the registry.
+    pub activity: Arc<ClientActivity>,
+}
+
+/// Activity of a connected client.
+#[derive(Default)]
+pub struct ClientActivity {
+    /// Messages waiting to be written to the client's websocket.
+    pub queued: AtomicUsize,
+    /// Messages put on the queue with `send` so far.
+    pub sent: AtomicUsize,
+    /// Messages taken from the queue to be written so far.
+    pub received: AtomicUsize,
+    /// When the client last sent a heartbeat.
+    pub last_heartbeat: Mutex<Option<Instant>>,
+}
+
+impl ClientActivity {
+    /// Put a message on the client's queue. Messages that don't end the
+    /// connection are sent through here, so that once `received` caught up
+    /// with `sent` as read after sending a message, the client took that
+    /// message off the queue.
+    pub fn send(&self, stream_writer: &UnboundedSender<Message>, message: Message) {
+        self.sent.fetch_add(1, Ordering::Relaxed);
+        let _res = stream_writer.send(message);
+    }
+}
+
+/// Registry of the clients connected to a bot, keyed by a connection ID.
+#[derive(Default)]
+pub struct Clients {
+    next_id: AtomicU64,
+    inner: Mutex<HashMap<u64, ClientInfo>>,
+}
+
+impl Clients {
+    /// Add a newly connected client, returning its connection ID and the
+    /// activity to update while it is connected.
+    pub fn register(
+        &self,
+        addr: SocketAddr,
+        compression: Option<Compression>,
+        encoding: Encoding,
+    ) -> (u64, Arc<ClientActivity>) {
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        let activity = Arc::new(ClientActivity::default());
+
+        self.inner.lock().unwrap().insert(
+            id,
+            ClientInfo {
+                addr,
+                connected_at: Instant::now(),
+                compression,
+                encoding,
+                shard_id: None,
+                compress: None,
+                intents: None,
+                seq: None,
+                activity: activity.clone(),
+            },
+        );
+
+        (id, activity)
+    }
+
+    /// Record the session settings of a client after it identified or resumed.
+    pub fn identified(
+        &self,
+        id: u64,
+        shard_id: u32,
+        compress: Option<bool>,
+        intents: Option<u64>,
+        seq: Arc<AtomicUsize>,
+    ) {
+        if let Some(client) = self.inner.lock().unwrap().get_mut(&id) {
+            client.shard_id = Some(shard_id);
+            client.compress = compress;
+            client.intents = intents;
+            client.seq = Some(seq);
+        }
+    }
+
+    /// Get a connected client by its connection ID.
+    pub fn get(&self, id: u64) -> Option<ClientInfo> {
+        self.inner.lock().unwrap().get(&id).cloned()
+    }
+
+    /// Remove a client that disconnected.
+    pub fn remove(&self, id: u64) {
+        self.inner.lock().unwrap().remove(&id);
+    }
+
+    /// Get all connected clients, ordered by connection ID.
+    pub fn all(&self) -> Vec<(u64, ClientInfo)> {
+        let mut clients: Vec<_> = self
+            .inner
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(id, client)| (*id, client.clone()))
+            .collect();
+        clients.sort_unstable_by_key(|(id, _)| *id);
+
+        clients
+    }
 }
 
-/// Global state for all shards managed by the proxy.
+/// State for all shards of a single bot managed by the proxy.
 pub struct Inner {
-    /// State of all shards managed by the proxy.
+    /// Name of the bot, used in logs and metrics.
+    pub name: String,
+    /// Token of the bot, which clients have to identify with. Shards identify
+    /// again when it changes.
+    pub token: watch::Sender<String>,
+    /// Tokens that clients may identify with instead of the bot token, by the
+    /// name of the consumer they are for.
+    pub consumer_tokens: HashMap<String, String>,
+    /// State of all shards of the bot managed by the proxy.
     pub shards: Vec<Arc<Shard>>,
+    /// Intents that the shards identify with.
+    pub intents: Intents,
     /// Total shard count.
     pub shard_count: u32,
+    /// Shard count recommended by Discord.
+    pub recommended_shard_count: u32,
+    /// Maximum amount of shards that may identify at the same time.
+    pub max_concurrency: u16,
     /// All sessions active in the proxy.
     pub sessions: RwLock<HashMap<String, Session>>,
+    /// All clients connected to the proxy.
+    pub clients: Clients,
+}
+
+/// Who a token sent by a client belongs to.
+pub enum TokenOwner<'a> {
+    /// The bot itself.
+    Bot,
+    /// A consumer with a token of its own, by its name.
+    Consumer(&'a str),
 }
 
 impl Inner {
-    /// Get a session by its ID.
+    /// Whether a token is the current token of the bot.
+    pub fn is_token(&self, token: &str) -> bool {
+        *self.token.borrow() == token
+    }
+
+    /// Find who a token that a client identifies with belongs to, if it is
+    /// accepted at all.
+    pub fn token_owner(&self, token: &str) -> Option<TokenOwner<'_>> {
+        if self.is_token(token) {
+            return Some(TokenOwner::Bot);
+        }
+
+        self.consumer_tokens
+            .iter()
+            .find(|(_, consumer_token)| *consumer_token == token)
+            .map(|(name, _)| TokenOwner::Consumer(name))
+    }
+
+    /// Get a shard running in this proxy by its ID.
+    pub fn shard(&self, shard_id: u32) -> Option<Arc<Shard>> {
+        // Shards are created in order, starting at the configured shard_start
+        let first = self.shards.first()?.id;
+
+        self.shards
+            .get(shard_id.checked_sub(first)? as usize)
+            .cloned()
+    }
+
+    /// Get a session by its ID, unless it expired.
     pub fn get_session(&self, session_id: &str) -> Option<Session> {
-        self.sessions.read().unwrap().get(session_id).cloned()
+        self.sessions
+            .read()
+            .unwrap()
+            .get(session_id)
+            .filter(|session| !session.is_expired(session_timeout()))
+            .cloned()
     }
 
     /// Create a new session.
@@ -99,14 +721,50 @@ impl Inner {
             .take(32)
             .collect();
 
-        self.sessions
-            .write()
-            .unwrap()
-            .insert(session_id.clone(), session);
+        let mut sessions = self.sessions.write().unwrap();
+
+        // Expired sessions can't be resumed anymore, remove them along with
+        // their history so that they don't pile up
+        sessions.retain(|_, session| !session.is_expired(session_timeout()));
+        sessions.insert(session_id.clone(), session);
 
         session_id
     }
+
+    /// Wait until every shard of the bot received a READY or failed.
+    pub async fn wait_until_ready(&self) {
+        for shard in &self.shards {
+            let _res = shard.wait_until_ready().await;
+        }
+    }
+}
+
+/// Time after which a session that no connection serves can't be resumed.
+fn session_timeout() -> Duration {
+    Duration::from_millis(CONFIG.session_timeout_ms)
 }
 
-/// A reference to the [`StateInner`] of the proxy.
+/// A reference to the [`Inner`] state of a bot.
 pub type State = Arc<Inner>;
+
+/// All bots managed by the proxy.
+pub struct Proxy {
+    /// The bot configured at the top level of the config, served at `/`.
+    pub default: State,
+    /// Additional bots by their name, served at `/bot/{name}`.
+    pub bots: HashMap<String, State>,
+}
+
+impl Proxy {
+    /// Iterate over all bots managed by the proxy.
+    pub fn iter(&self) -> impl Iterator<Item = &State> {
+        iter::once(&self.default).chain(self.bots.values())
+    }
+
+    /// Wait until every shard of every bot received a READY.
+    pub async fn wait_until_ready(&self) {
+        for state in self.iter() {
+            state.wait_until_ready().await;
+        }
+    }
+}
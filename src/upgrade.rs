@@ -3,29 +3,173 @@ use http_body_util::Full;
 use hyper::{
     body::{Bytes, Incoming},
     header::{
-        HeaderValue, CONNECTION, SEC_WEBSOCKET_ACCEPT, SEC_WEBSOCKET_KEY, SEC_WEBSOCKET_VERSION,
-        UPGRADE,
+        HeaderValue, AUTHORIZATION, CONNECTION, FORWARDED, RETRY_AFTER, SEC_WEBSOCKET_ACCEPT,
+        SEC_WEBSOCKET_KEY, SEC_WEBSOCKET_PROTOCOL, SEC_WEBSOCKET_VERSION, UPGRADE,
     },
     http::StatusCode,
     upgrade, Request, Response,
 };
 use hyper_util::rt::TokioIo;
-use ring::digest;
-use tracing::error;
+use ring::{constant_time::verify_slices_are_equal, digest};
+use tracing::{error, warn};
 
-use std::net::SocketAddr;
+use std::{
+    net::{IpAddr, SocketAddr},
+    sync::atomic::Ordering,
+};
+
+use crate::{
+    compression::Compression,
+    config::{CacheProfile, CONFIG},
+    encoding::Encoding,
+    server::{handle_client, ClientSlot},
+    state::State,
+    SHUTDOWN,
+};
 
-use crate::{server::handle_client, state::State};
+/// Seconds that clients rejected because of `max_clients` are told to wait
+/// before trying again.
+const MAX_CLIENTS_RETRY_AFTER: &str = "5";
 
 /// Websocket GUID constant as specified in RFC6455:
 /// <https://datatracker.ietf.org/doc/html/rfc6455#section-1.3>
 const GUID: &str = "258EAFA5-E914-47DA-95CA-C5AB0DC85B11";
 
+/// Find the value of a parameter in a query string.
+pub fn query_param<'a>(query: &'a str, name: &str) -> Option<&'a str> {
+    query
+        .split('&')
+        .find_map(|pair| pair.split_once('=').filter(|(key, _)| *key == name))
+        .map(|(_, value)| value)
+}
+
+/// Find the address of the client that sent a request. Connections from the
+/// `trusted_proxies` use the last address in the `Forwarded` or
+/// `X-Forwarded-For` header that wasn't added by one of them, otherwise the
+/// headers could be spoofed. The port is the one of the connection.
+pub fn client_addr(addr: SocketAddr, request: &Request<Incoming>) -> SocketAddr {
+    let is_trusted = |ip: &IpAddr| CONFIG.trusted_proxies.contains(&ip.to_canonical());
+
+    if !CONFIG.trust_x_forwarded_for || !is_trusted(&addr.ip()) {
+        return addr;
+    }
+
+    let headers = request.headers();
+
+    // Forwarded is the standardized version of X-Forwarded-For, so it wins
+    let forwarded: Vec<IpAddr> = if headers.contains_key(FORWARDED) {
+        headers
+            .get_all(FORWARDED)
+            .iter()
+            .filter_map(|value| value.to_str().ok())
+            .flat_map(|value| value.split(','))
+            .filter_map(|element| {
+                element.split(';').find_map(|pair| {
+                    let (key, value) = pair.trim().split_once('=')?;
+
+                    if key.eq_ignore_ascii_case("for") {
+                        parse_forwarded_ip(value)
+                    } else {
+                        None
+                    }
+                })
+            })
+            .collect()
+    } else {
+        headers
+            .get_all("x-forwarded-for")
+            .iter()
+            .filter_map(|value| value.to_str().ok())
+            .flat_map(|value| value.split(','))
+            .filter_map(parse_forwarded_ip)
+            .collect()
+    };
+
+    // The closest proxies are at the end
+    forwarded
+        .into_iter()
+        .rev()
+        .find(|ip| !is_trusted(ip))
+        .map_or(addr, |ip| SocketAddr::new(ip, addr.port()))
+}
+
+/// Parse an address from a forwarding header, which may be quoted and have a
+/// port, with IPv6 addresses in brackets.
+fn parse_forwarded_ip(value: &str) -> Option<IpAddr> {
+    let value = value.trim().trim_matches('"');
+
+    let ip: IpAddr = if let Some(rest) = value.strip_prefix('[') {
+        rest.split_once(']')?.0.parse().ok()?
+    } else {
+        value
+            .parse()
+            .or_else(|_| value.parse::<SocketAddr>().map(|addr| addr.ip()))
+            .ok()?
+    };
+
+    Some(ip.to_canonical())
+}
+
+/// Check the client secret, if one is configured. It may be sent in the
+/// `Authorization` header, optionally prefixed like `Bearer`, or in the
+/// `client_secret` query string parameter.
+fn is_authorized(request: &Request<Incoming>) -> bool {
+    let Some(secret) = &CONFIG.client_secret else {
+        return true;
+    };
+
+    let provided = request
+        .headers()
+        .get(AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.split_whitespace().last())
+        .or_else(|| {
+            request
+                .uri()
+                .query()
+                .and_then(|query| query_param(query, "client_secret"))
+        });
+
+    provided.is_some_and(|provided| {
+        verify_slices_are_equal(provided.as_bytes(), secret.as_bytes()).is_ok()
+    })
+}
+
+/// Pick the first of the subprotocols requested by a client that is in the
+/// `subprotocols` config. Fails if the client requested some, but none of them
+/// are supported.
+fn select_subprotocol(request: &Request<Incoming>) -> Result<Option<&'static str>, ()> {
+    let mut requested = request
+        .headers()
+        .get_all(SEC_WEBSOCKET_PROTOCOL)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .map(str::trim)
+        .filter(|protocol| !protocol.is_empty())
+        .peekable();
+
+    if requested.peek().is_none() {
+        return Ok(None);
+    }
+
+    requested
+        .find_map(|protocol| {
+            CONFIG
+                .subprotocols
+                .iter()
+                .find(|supported| supported.as_str() == protocol)
+        })
+        .map(|protocol| Some(protocol.as_str()))
+        .ok_or(())
+}
+
 /// Accept a websocket upgrade request and start processing the client's
 /// events afterwards.
 ///
 /// This method is one of two parts in the communication between server
-/// and client where zlib-stream compression may be requested.
+/// and client where zlib-stream (or gzip-stream) compression may be requested.
+#[allow(clippy::too_many_lines)]
 pub fn server(
     addr: SocketAddr,
     mut request: Request<Incoming>,
@@ -34,17 +178,94 @@ pub fn server(
     let uri = request.uri();
     let query = uri.query();
 
-    // Track whether the client requested zlib encoding in the query
+    // Track whether the client requested transport compression in the query
     // string parameters
-    let use_zlib = query.map_or(false, |q| q.contains("compress=zlib-stream"));
+    let compression = query
+        .and_then(|query| query_param(query, "compress"))
+        .and_then(Compression::from_query);
+
+    let encoding = match query.and_then(|query| query_param(query, "encoding")) {
+        Some(value) => Encoding::from_query(value),
+        None => Some(Encoding::default()),
+    };
+
+    // Clients that can't keep a decompression context across messages opt out
+    // of context takeover
+    let context_takeover = match query.and_then(|query| query_param(query, "context_takeover")) {
+        Some("true") | None => Some(true),
+        Some("false") => Some(false),
+        Some(_) => None,
+    };
+
+    let cache_profile = match query.and_then(|query| query_param(query, "cache_profile")) {
+        Some(name) => CONFIG.cache_profiles.get(name),
+        None => Some(&CacheProfile::ALL),
+    };
 
     let mut response = Response::new(Full::default());
 
-    if request.headers().get(UPGRADE).and_then(|v| v.to_str().ok()) != Some("websocket") {
+    if !request
+        .headers()
+        .get(UPGRADE)
+        .and_then(|v| v.to_str().ok())
+        .is_some_and(|v| v.eq_ignore_ascii_case("websocket"))
+    {
+        *response.status_mut() = StatusCode::BAD_REQUEST;
+        return response;
+    }
+
+    let Some(encoding) = encoding else {
+        warn!("[{addr}] Client requested an unsupported encoding, rejecting upgrade");
+        *response.status_mut() = StatusCode::BAD_REQUEST;
+        return response;
+    };
+
+    let Some(context_takeover) = context_takeover else {
+        warn!("[{addr}] Client sent an invalid context_takeover, rejecting upgrade");
+        *response.status_mut() = StatusCode::BAD_REQUEST;
+        return response;
+    };
+
+    let Some(cache_profile) = cache_profile else {
+        warn!("[{addr}] Client requested an unknown cache profile, rejecting upgrade");
         *response.status_mut() = StatusCode::BAD_REQUEST;
         return response;
+    };
+
+    // Clients without a supported subprotocol are accepted without one, which
+    // most client libraries are fine with, unless this is strict
+    let subprotocol = match select_subprotocol(&request) {
+        Ok(subprotocol) => subprotocol,
+        Err(()) if CONFIG.strict_subprotocols => {
+            warn!("[{addr}] Client requested no supported subprotocol, rejecting upgrade");
+            *response.status_mut() = StatusCode::BAD_REQUEST;
+            return response;
+        }
+        Err(()) => None,
+    };
+
+    if !is_authorized(&request) {
+        warn!("[{addr}] Client secret missing or mismatched, rejecting upgrade");
+        *response.status_mut() = StatusCode::UNAUTHORIZED;
+        return response;
     }
 
+    if SHUTDOWN.load(Ordering::Relaxed) {
+        warn!("[{addr}] Proxy is shutting down, rejecting upgrade");
+        *response.status_mut() = StatusCode::SERVICE_UNAVAILABLE;
+        return response;
+    }
+
+    let Some(client_slot) = ClientSlot::acquire(encoding, compression) else {
+        warn!("[{addr}] Maximum amount of clients reached, rejecting upgrade");
+        *response.status_mut() = StatusCode::SERVICE_UNAVAILABLE;
+        response.headers_mut().insert(
+            RETRY_AFTER,
+            HeaderValue::from_static(MAX_CLIENTS_RETRY_AFTER),
+        );
+        return response;
+    };
+
     if let Some(websocket_key) = request.headers().get(SEC_WEBSOCKET_KEY) {
         let mut ctx = digest::Context::new(&digest::SHA1_FOR_LEGACY_USE_ONLY);
         ctx.update(websocket_key.as_bytes());
@@ -56,7 +277,17 @@ pub fn server(
         tokio::spawn(async move {
             match upgrade::on(&mut request).await {
                 Ok(upgraded) => {
-                    let _res = handle_client(addr, TokioIo::new(upgraded), state, use_zlib).await;
+                    let _res = handle_client(
+                        addr,
+                        TokioIo::new(upgraded),
+                        state,
+                        compression,
+                        context_takeover,
+                        encoding,
+                        cache_profile,
+                    )
+                    .await;
+                    drop(client_slot);
                 }
                 Err(e) => error!("[{}] Websocket upgrade error: {}", addr, e),
             }
@@ -76,6 +307,14 @@ pub fn server(
         response
             .headers_mut()
             .insert(SEC_WEBSOCKET_VERSION, HeaderValue::from_static("13"));
+
+        // The client sent the subprotocol in a header, so it is a valid value
+        if let Some(subprotocol) = subprotocol {
+            response.headers_mut().insert(
+                SEC_WEBSOCKET_PROTOCOL,
+                HeaderValue::from_str(subprotocol).unwrap(),
+            );
+        }
     } else {
         *response.status_mut() = StatusCode::BAD_REQUEST;
     }